@@ -3,8 +3,39 @@ use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 pub struct Arguments {
+    /// Required unless a subcommand is used
     #[structopt(long, short("f"))]
-    pub plan_file: Utf8PathBuf,
+    pub plan_file: Option<Utf8PathBuf>,
+    /// Refreshes the repository listing even when it has not expired
     #[structopt(long, short)]
     pub skip_repository_cache: bool,
+    /// Seconds a repository listing is used before it is refreshed, overrides the plan
+    #[structopt(long)]
+    pub repository_cache_ttl: Option<u64>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Inspects, clears or prunes cached repository listings and cloned repositories
+    Cache(CacheCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CacheCommand {
+    /// Shows cached repository listings and cloned repositories
+    List,
+    /// Removes cached repository listings and cloned repositories, both unless one is chosen
+    Clear {
+        #[structopt(long)]
+        listings: bool,
+        #[structopt(long)]
+        repositories: bool,
+    },
+    /// Removes clones of repositories missing from their listing or not used for a while
+    Prune {
+        #[structopt(long, default_value = "30")]
+        unused_days: i64,
+    },
 }
@@ -0,0 +1,415 @@
+use std::collections::{HashMap, HashSet};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::{eyre::Context, Result};
+use tokio::{fs, task};
+use tracing::{debug, info};
+
+use crate::arguments::CacheCommand;
+use crate::providers::cache::listings;
+
+/// Files git rewrites when a clone is fetched or checked out, the newest tells when it was last used
+const USAGE_FILES: &[&str] = &["FETCH_HEAD", "index", "HEAD"];
+
+#[tracing::instrument]
+pub async fn run(command: &CacheCommand, cache_directory: &Utf8Path) -> Result<()> {
+    match command {
+        CacheCommand::List => list(cache_directory).await,
+        CacheCommand::Clear {
+            listings,
+            repositories,
+        } => {
+            // Neither flag means everything
+            let all = !listings && !repositories;
+            if *listings || all {
+                clear_listings(cache_directory).await?;
+            }
+            if *repositories || all {
+                clear_repositories(cache_directory).await?;
+            }
+            Ok(())
+        }
+        CacheCommand::Prune { unused_days } => {
+            prune(cache_directory, Duration::days(*unused_days)).await
+        }
+    }
+}
+
+/// A clone under `repos/<provider>/<host>/<organization>/<name>` or a worktree under
+/// `worktrees/<provider>/<host>/<organization>/<repository>/<plan>@<branch>`
+#[derive(Debug)]
+struct CacheEntry {
+    path: Utf8PathBuf,
+    /// Missing on clones kept directly under `repos/` by older versions
+    namespace: Option<String>,
+    name: String,
+    last_used: DateTime<Utc>,
+}
+
+impl CacheEntry {
+    fn display_name(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, self.name),
+            None => format!("{} (old layout)", self.name),
+        }
+    }
+}
+
+async fn list(cache_directory: &Utf8Path) -> Result<()> {
+    println!("Repository listings in {}", cache_directory);
+    for (path, listing) in listings(cache_directory).await? {
+        let name = path.file_stem().unwrap_or_default();
+        match listing {
+            Some(listing) => println!(
+                "  {} {} repositories fetched {} ago from {}",
+                name,
+                listing.repository_count(),
+                format_age(Utc::now() - listing.fetched_at),
+                listing.api_url
+            ),
+            None => println!("  {} old format, refreshed on the next run", name),
+        }
+    }
+
+    let repositories_directory = cache_directory.join("repos");
+    println!("Cloned repositories in {}", repositories_directory);
+    let mut total = 0;
+    for clone in clones(&repositories_directory).await? {
+        let size = directory_size(&clone.path).await?;
+        total += size;
+        println!(
+            "  {} {} last used {} ago",
+            clone.display_name(),
+            format_size(size),
+            format_age(Utc::now() - clone.last_used)
+        );
+    }
+
+    let worktrees_directory = cache_directory.join("worktrees");
+    println!("Worktrees in {}", worktrees_directory);
+    for worktree in worktrees(&worktrees_directory).await? {
+        let size = directory_size(&worktree.path).await?;
+        total += size;
+        println!(
+            "  {} {} last used {} ago",
+            worktree.display_name(),
+            format_size(size),
+            format_age(Utc::now() - worktree.last_used)
+        );
+    }
+    println!("Total {}", format_size(total));
+    Ok(())
+}
+
+async fn clear_listings(cache_directory: &Utf8Path) -> Result<()> {
+    for (path, _) in listings(cache_directory).await? {
+        fs::remove_file(&path)
+            .await
+            .wrap_err_with(|| format!("failed to remove {}", path))?;
+        info!("removed {}", path);
+    }
+    Ok(())
+}
+
+async fn clear_repositories(cache_directory: &Utf8Path) -> Result<()> {
+    for directory in &["repos", "worktrees"] {
+        let directory = cache_directory.join(directory);
+        if directory.exists() {
+            fs::remove_dir_all(&directory)
+                .await
+                .wrap_err_with(|| format!("failed to remove {}", directory))?;
+            info!("removed {}", directory);
+        }
+    }
+    Ok(())
+}
+
+/// Removes clones of repositories missing from their cached listing or not used for `max_unused`,
+/// and the worktrees of removed clones
+async fn prune(cache_directory: &Utf8Path, max_unused: Duration) -> Result<()> {
+    let mut listed: HashMap<String, HashSet<String>> = HashMap::new();
+    for (_, listing) in listings(cache_directory).await? {
+        match listing {
+            Some(listing) if !listing.clone_namespace.is_empty() => {
+                let names = listing.repository_names().map(str::to_owned).collect();
+                listed.insert(listing.clone_namespace, names);
+            }
+            _ => {}
+        }
+    }
+
+    let repositories_directory = cache_directory.join("repos");
+    let mut freed = 0;
+    for clone in clones(&repositories_directory).await? {
+        let reason = prune_reason(&clone, &listed, Utc::now(), max_unused);
+        freed += remove_entry(&clone, reason).await?;
+    }
+    for worktree in worktrees(&cache_directory.join("worktrees")).await? {
+        let clone = worktree
+            .namespace
+            .as_ref()
+            .map(|namespace| repositories_directory.join(namespace));
+        let reason = match clone {
+            Some(clone) if clone.exists() => {
+                prune_reason(&worktree, &HashMap::new(), Utc::now(), max_unused)
+            }
+            _ => Some("its clone was removed"),
+        };
+        freed += remove_entry(&worktree, reason).await?;
+    }
+    info!("freed {}", format_size(freed));
+    Ok(())
+}
+
+/// Returns how many bytes were freed
+async fn remove_entry(entry: &CacheEntry, reason: Option<&str>) -> Result<u64> {
+    let reason = match reason {
+        Some(reason) => reason,
+        None => {
+            debug!("keeping {}", entry.display_name());
+            return Ok(0);
+        }
+    };
+    let size = directory_size(&entry.path).await?;
+    fs::remove_dir_all(&entry.path)
+        .await
+        .wrap_err_with(|| format!("failed to remove {}", entry.path))?;
+    info!(
+        "removed {} ({}), {}",
+        entry.display_name(),
+        format_size(size),
+        reason
+    );
+    Ok(size)
+}
+
+fn prune_reason(
+    clone: &CacheEntry,
+    listed: &HashMap<String, HashSet<String>>,
+    now: DateTime<Utc>,
+    max_unused: Duration,
+) -> Option<&'static str> {
+    let namespace = match &clone.namespace {
+        Some(namespace) => namespace,
+        None => return Some("cloned by an older version"),
+    };
+    if let Some(names) = listed.get(namespace) {
+        if !names.contains(&clone.name) {
+            return Some("no longer listed");
+        }
+    }
+    if now - clone.last_used > max_unused {
+        return Some("not used recently");
+    }
+    None
+}
+
+async fn clones(repositories_directory: &Utf8Path) -> Result<Vec<CacheEntry>> {
+    let mut output = vec![];
+    for provider in subdirectories(repositories_directory).await? {
+        if provider.join(".git").exists() {
+            output.push(cache_entry(repositories_directory, provider, false).await?);
+            continue;
+        }
+        // Bare clones, or clones with a working copy made before worktrees were used
+        for directory in nested_directories(&provider, 3).await? {
+            if directory.join("HEAD").exists() || directory.join(".git").exists() {
+                output.push(cache_entry(repositories_directory, directory, true).await?);
+            }
+        }
+    }
+    output.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(output)
+}
+
+async fn worktrees(worktrees_directory: &Utf8Path) -> Result<Vec<CacheEntry>> {
+    let mut output = vec![];
+    for directory in nested_directories(worktrees_directory, 5).await? {
+        output.push(cache_entry(worktrees_directory, directory, true).await?);
+    }
+    output.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(output)
+}
+
+async fn cache_entry(root: &Utf8Path, path: Utf8PathBuf, namespaced: bool) -> Result<CacheEntry> {
+    let namespace = path
+        .strip_prefix(root)
+        .ok()
+        .and_then(|relative| relative.parent())
+        .map(|namespace| namespace.as_str().replace('\\', "/"))
+        .filter(|_| namespaced);
+    let git_directory = git_directory(&path).await;
+    let mut last_used = DateTime::<Utc>::from(std::time::UNIX_EPOCH);
+    for file in USAGE_FILES {
+        if let Ok(modified) = fs::metadata(git_directory.join(file))
+            .await
+            .and_then(|m| m.modified())
+        {
+            last_used = last_used.max(modified.into());
+        }
+    }
+    Ok(CacheEntry {
+        name: path.file_name().unwrap_or_default().to_owned(),
+        path,
+        namespace,
+        last_used,
+    })
+}
+
+/// `.git` of a clone, the folder `.git` points to on worktrees, or the bare clone itself
+async fn git_directory(path: &Utf8Path) -> Utf8PathBuf {
+    let dot_git = path.join(".git");
+    if dot_git.is_file() {
+        if let Ok(contents) = fs::read_to_string(&dot_git).await {
+            if let Some(directory) = contents.trim().strip_prefix("gitdir: ") {
+                return Utf8PathBuf::from(directory);
+            }
+        }
+    }
+    if dot_git.is_dir() {
+        return dot_git;
+    }
+    path.to_owned()
+}
+
+/// Folders exactly `depth` levels below `directory`
+async fn nested_directories(directory: &Utf8Path, depth: usize) -> Result<Vec<Utf8PathBuf>> {
+    let mut output = vec![directory.to_owned()];
+    for _ in 0..depth {
+        let mut next = vec![];
+        for directory in &output {
+            next.extend(subdirectories(directory).await?);
+        }
+        output = next;
+    }
+    Ok(output)
+}
+
+async fn subdirectories(directory: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let mut output = vec![];
+    if !directory.is_dir() {
+        return Ok(output);
+    }
+    let mut entries = fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        if let Ok(path) = Utf8PathBuf::from_path_buf(entry.path()) {
+            output.push(path);
+        }
+    }
+    Ok(output)
+}
+
+async fn directory_size(directory: &Utf8Path) -> Result<u64> {
+    let directory = directory.to_owned();
+    let size = task::spawn_blocking(move || walkdir_size(directory.as_ref())).await?;
+    Ok(size)
+}
+
+fn walkdir_size(directory: &std::path::Path) -> u64 {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => walkdir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn format_age(age: Duration) -> String {
+    if age.num_days() > 0 {
+        format!("{}d", age.num_days())
+    } else if age.num_hours() > 0 {
+        format!("{}h", age.num_hours())
+    } else if age.num_minutes() > 0 {
+        format!("{}m", age.num_minutes())
+    } else {
+        format!("{}s", age.num_seconds().max(0))
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use chrono::{Duration, Utc};
+
+    use super::{format_age, format_size, prune_reason, CacheEntry};
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::seconds(42)), "42s");
+        assert_eq!(format_age(Duration::minutes(90)), "1h");
+        assert_eq!(format_age(Duration::days(3)), "3d");
+        assert_eq!(format_age(Duration::seconds(-5)), "0s");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024 / 2), "1.5 GiB");
+    }
+
+    #[test]
+    fn test_prune_reason() {
+        let now = Utc::now();
+        let clone = |namespace: Option<&str>, name: &str, days: i64| CacheEntry {
+            path: format!("repos/{}", name).into(),
+            namespace: namespace.map(str::to_owned),
+            name: name.to_owned(),
+            last_used: now - Duration::days(days),
+        };
+        let mut listed = HashMap::new();
+        listed.insert(
+            "github/github.com/org".to_owned(),
+            vec!["kept".to_owned()].into_iter().collect::<HashSet<_>>(),
+        );
+        let max_unused = Duration::days(30);
+        let reason = |clone| prune_reason(&clone, &listed, now, max_unused);
+
+        assert_eq!(
+            reason(clone(Some("github/github.com/org"), "kept", 1)),
+            None
+        );
+        assert_eq!(
+            reason(clone(Some("github/github.com/org"), "deleted", 1)),
+            Some("no longer listed")
+        );
+        assert_eq!(
+            reason(clone(Some("github/github.com/org"), "kept", 31)),
+            Some("not used recently")
+        );
+        // Without a listing only the age counts
+        assert_eq!(
+            reason(clone(Some("github/github.com/other"), "any", 1)),
+            None
+        );
+        assert_eq!(
+            reason(clone(None, "kept", 1)),
+            Some("cloned by an older version")
+        );
+    }
+}
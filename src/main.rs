@@ -1,18 +1,23 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
-use color_eyre::{eyre::Context, Result};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 use tokio::{sync::Semaphore, task};
-use tracing::info;
+use tracing::{debug, info, warn};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::arguments::Arguments;
+use crate::arguments::{Arguments, Command};
 use crate::constants::CACHE_DIR;
 use crate::plan::{plan_from_file, PlanExecutor};
+use crate::providers::cache::DEFAULT_TTL;
 
 mod arguments;
+mod cache;
 mod constants;
 mod plan;
 mod providers;
@@ -22,8 +27,31 @@ pub struct Repository {
     name: String,
     private: bool,
     fork: bool,
+    #[serde(default)]
+    archived: bool,
     ssh_url: String,
+    /// Older repository caches do not have it
+    #[serde(default)]
+    clone_url: String,
     default_branch: String,
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default)]
+    topics: Vec<String>,
+    /// What the authenticated user can do, unknown on older repository caches
+    #[serde(default)]
+    permissions: Option<RepositoryPermissions>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepositoryPermissions {
+    push: bool,
+}
+
+impl Repository {
+    pub fn can_push(&self) -> bool {
+        self.permissions.as_ref().map(|p| p.push).unwrap_or(true)
+    }
 }
 
 pub(crate) fn setup_error_handlers() -> Result<()> {
@@ -49,17 +77,67 @@ async fn main() -> Result<()> {
     setup_error_handlers()?;
 
     let arguments = Arguments::from_args();
+    if let Some(Command::Cache(command)) = &arguments.command {
+        return cache::run(command, &CACHE_DIR).await;
+    }
+    let plan_file = arguments
+        .plan_file
+        .as_ref()
+        .ok_or_else(|| eyre!("--plan-file is required"))?;
     info!("parsing plan");
-    let plan = Arc::new(plan_from_file(&arguments.plan_file).await?);
+    let mut plan = plan_from_file(plan_file).await?;
+    plan.resolve_credentials().await?;
     let provider = plan.get_provider();
-    let all_repositories = provider
-        .list_repositories(!arguments.skip_repository_cache)
-        .await?;
+    let max_age = if arguments.skip_repository_cache {
+        Duration::from_secs(0)
+    } else {
+        arguments
+            .repository_cache_ttl
+            .map(Duration::from_secs)
+            .or_else(|| plan.repository_cache_ttl())
+            .unwrap_or(DEFAULT_TTL)
+    };
+    let all_repositories = provider.list_repositories(max_age).await?;
 
-    let executors = all_repositories
+    let repositories = all_repositories
         .into_iter()
         .filter(|repository| plan.repository_allowed(&repository.name))
-        .map(|repository| PlanExecutor::new(plan.clone(), repository, &CACHE_DIR))
+        .filter(|repository| {
+            if repository.archived {
+                debug!("skipping archived repository {}", repository.name);
+            }
+            !repository.archived
+        })
+        .collect::<Vec<_>>();
+    let plans = repositories
+        .into_iter()
+        .map(|repository| Ok((Arc::new(plan.for_repository(&repository.name)?), repository)))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Branch names can use the variables of each repository
+    let mut repositories_by_branch = BTreeMap::<_, Vec<_>>::new();
+    for (plan, repository) in &plans {
+        repositories_by_branch
+            .entry(plan.branch_name())
+            .or_default()
+            .push(repository.name.clone());
+    }
+    for (branch_name, repository_names) in repositories_by_branch {
+        // Each repository is checked again on its own when this fails
+        if let Err(err) = provider
+            .prefetch_open_prs(&repository_names, branch_name)
+            .await
+        {
+            warn!(
+                "failed to check open pull requests on {} of all repositories at once: {:?}",
+                branch_name, err
+            );
+        }
+    }
+
+    let executors = plans
+        .into_iter()
+        .map(|(plan, repository)| PlanExecutor::new(plan, repository, &CACHE_DIR))
         .collect::<Vec<_>>();
 
     let mut futures = vec![];
@@ -77,7 +155,7 @@ async fn main() -> Result<()> {
     }
 
     for future in futures {
-        let _response = future.await??;
+        future.await??;
     }
 
     info!("process done");
@@ -0,0 +1,74 @@
+use serde::Deserialize;
+
+use super::OnFailure;
+
+/// Added to every commit, so commits pushed by people to the branch can be told apart
+pub const COMMIT_TRAILER: &str = "Generated-by: there-i-fixed-it";
+
+/// What happens to a branch that a previous run already pushed
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BranchUpdate {
+    #[serde(default)]
+    pub strategy: BranchStrategy,
+    /// Rebase conflicts and commits that would be dropped by regenerating the branch
+    #[serde(default)]
+    pub on_conflict: OnFailure,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchStrategy {
+    /// Starts the branch again from the default branch and force pushes it
+    #[default]
+    Regenerate,
+    /// Rebases the branch onto the default branch, keeping commits pushed by others
+    Rebase,
+    /// Leaves branches with commits not made by the tool alone, regenerates the others
+    KeepEdited,
+}
+
+/// Separates the fields and the commits of `FOREIGN_COMMITS_FORMAT`
+const FIELD_SEPARATOR: char = '\u{1f}';
+const COMMIT_SEPARATOR: char = '\u{1e}';
+/// `git log` format parsed by `foreign_commits`
+pub const FOREIGN_COMMITS_FORMAT: &str = "--format=%h%x1f%an%x1f%B%x1e";
+
+/// Commits of a `git log` with `FOREIGN_COMMITS_FORMAT` that the tool did not make, as
+/// `<hash> <author>: <subject>`. Commits made before the trailer existed are recognised by their message.
+pub fn foreign_commits(log: &str, git_message: &str) -> Vec<String> {
+    let git_subject = git_message.lines().next().unwrap_or_default().trim();
+    log.split(COMMIT_SEPARATOR)
+        .filter_map(|commit| {
+            let mut fields = commit.trim_start().splitn(3, FIELD_SEPARATOR);
+            let hash = fields.next().filter(|hash| !hash.is_empty())?;
+            let author = fields.next().unwrap_or_default();
+            let message = fields.next().unwrap_or_default();
+            let subject = message.lines().next().unwrap_or_default().trim();
+            let ours =
+                message.lines().any(|line| line.trim() == COMMIT_TRAILER) || subject == git_subject;
+            if ours {
+                return None;
+            }
+            Some(format!("{} {}: {}", hash, author, subject))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::foreign_commits;
+
+    #[test]
+    fn test_foreign_commits() {
+        let log = "a1b2c3d\u{1f}Reviewer\u{1f}Fix typo\n\u{1e}\n\
+                   e4f5a6b\u{1f}Bot\u{1f}chore: Changes\n\nGenerated-by: there-i-fixed-it\n\u{1e}\n\
+                   c7d8e9f\u{1f}Bot\u{1f}chore: Changes\n\u{1e}\n";
+
+        assert_eq!(
+            foreign_commits(log, "chore: Changes"),
+            vec!["a1b2c3d Reviewer: Fix typo"]
+        );
+        assert!(foreign_commits("", "chore: Changes").is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeSet;
+
+use serde::Deserialize;
+
+use super::Plan;
+
+/// How much of each repository is downloaded and checked out
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CloneOptions {
+    /// Only the last `depth` commits of the default branch
+    pub depth: Option<u32>,
+    /// File contents are downloaded when checked out instead of when cloning
+    #[serde(default)]
+    pub blobless: bool,
+    /// Checks out only the folders the plan can change
+    #[serde(default)]
+    pub sparse: bool,
+}
+
+impl CloneOptions {
+    pub fn clone_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(depth) = self.depth {
+            args.push(format!("--depth={}", depth));
+        }
+        if self.blobless {
+            args.push("--filter=blob:none".to_owned());
+        }
+        args
+    }
+}
+
+/// Part of the repository a path pattern can reach
+#[derive(Debug, PartialEq)]
+enum Reach {
+    /// Files on the root, which sparse checkouts always have
+    RootFiles,
+    Directory(String),
+    Everything,
+}
+
+/// Folders that sparse checkouts need for the plan, `None` when it can change files in any folder.
+/// `patches` are the contents of the plan patches.
+pub fn sparse_directories(plan: &Plan, patches: &[String]) -> Option<Vec<String>> {
+    let mut reaches = vec![];
+    for operation in &plan.file_operations {
+        // Commands like lockfile updates read and write files outside of the plan patterns
+        if operation.processors.iter().any(|p| p.has_post_command()) {
+            return None;
+        }
+        reaches.push(reach(operation.pattern.as_str()));
+        if let Some(rename_to) = &operation.rename_to {
+            reaches.push(reach(rename_to));
+        }
+        let conditions =
+            std::iter::once(&operation.when).chain(operation.processors.iter().map(|p| &p.when));
+        for condition in conditions {
+            reaches.extend(condition.file_paths().map(|p| reach(p.as_str())));
+        }
+    }
+    for creation in &plan.file_creations {
+        reaches.push(reach(creation.path.as_str()));
+    }
+    for patch in patches {
+        reaches.extend(patch_paths(patch).map(reach));
+    }
+
+    let mut directories = BTreeSet::new();
+    for reach in reaches {
+        match reach {
+            Reach::RootFiles => {}
+            Reach::Directory(directory) => {
+                directories.insert(directory);
+            }
+            Reach::Everything => return None,
+        }
+    }
+    Some(directories.into_iter().collect())
+}
+
+/// Folders before the first wildcard, `$1` of rename destinations counts as one
+fn reach(pattern: &str) -> Reach {
+    let components = pattern
+        .trim_start_matches("./")
+        .split('/')
+        .collect::<Vec<_>>();
+    let (file, folders) = match components.split_last() {
+        Some(split) => split,
+        None => return Reach::RootFiles,
+    };
+    let is_wildcard = |c: &&str| c.contains(['*', '?', '[', '{', '$']);
+    let literal = folders
+        .iter()
+        .take_while(|c| !is_wildcard(c))
+        .copied()
+        .collect::<Vec<_>>();
+    match (literal.is_empty(), folders.is_empty()) {
+        (false, _) => Reach::Directory(literal.join("/")),
+        // `**` on its own matches files in any folder
+        (true, true) if *file != "**" => Reach::RootFiles,
+        (true, _) => Reach::Everything,
+    }
+}
+
+/// Paths changed by a unified diff
+fn patch_paths(patch: &str) -> impl Iterator<Item = &str> {
+    patch.lines().filter_map(|line| {
+        let path = line
+            .strip_prefix("--- ")
+            .or_else(|| line.strip_prefix("+++ "))?;
+        // Timestamps of plain diffs come after a tab
+        let path = path.split('\t').next()?.trim();
+        if path == "/dev/null" {
+            return None;
+        }
+        Some(
+            path.strip_prefix("a/")
+                .or_else(|| path.strip_prefix("b/"))
+                .unwrap_or(path),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reach, sparse_directories, Reach};
+    use crate::plan::plan_from_str;
+
+    #[test]
+    fn test_reach() {
+        assert_eq!(
+            reach(".github/workflows/*.yml"),
+            Reach::Directory(".github/workflows".to_string())
+        );
+        assert_eq!(reach("docs/**/*.md"), Reach::Directory("docs".to_string()));
+        assert_eq!(reach("*.md"), Reach::RootFiles);
+        assert_eq!(reach("CODEOWNERS"), Reach::RootFiles);
+        assert_eq!(reach("**/*.tf"), Reach::Everything);
+        assert_eq!(reach("**"), Reach::Everything);
+        assert_eq!(reach("$1/README.md"), Reach::Everything);
+    }
+
+    #[test]
+    fn test_sparse_directories() {
+        let plan = plan_from_str(
+            r#"
+            branch_name = "test"
+            git_message = "test"
+            repositories = ["*"]
+            provider = { name = "test" }
+
+            [[files]]
+            glob = ".github/workflows/*.yml"
+            when = { file_exists = "ci/config.yml" }
+
+            [[files]]
+            glob = "docs/*.rst"
+            rename_to = "documentation/$1.md"
+
+            [[create_files]]
+            path = ".github/CODEOWNERS"
+            contents = "* @owners"
+            "#,
+        )
+        .unwrap();
+        let patch = "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-old\n+new\n--- /dev/null\n+++ b/README.md\n@@ -0,0 +1 @@\n+readme\n";
+
+        assert_eq!(
+            sparse_directories(&plan, &[patch.to_string()]).unwrap(),
+            vec![
+                ".github",
+                ".github/workflows",
+                "ci",
+                "docs",
+                "documentation",
+                "src"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sparse_directories_with_post_commands() {
+        let plan = plan_from_str(
+            r#"
+            branch_name = "test"
+            git_message = "test"
+            repositories = ["*"]
+            provider = { name = "test" }
+
+            [[files]]
+            glob = "services/api/package.json"
+            processors = [{ type = "dependency", name = "left-pad", version = "2.0", update_lockfile = true }]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(sparse_directories(&plan, &[]), None);
+    }
+}
@@ -0,0 +1,140 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::Repository;
+
+/// Predicates used by `when`, every condition that is set has to match
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    #[serde(default, with = "serde_regex")]
+    contains: Option<Regex>,
+    #[serde(default, with = "serde_regex")]
+    not_contains: Option<Regex>,
+    /// Path relative to the repository root
+    file_exists: Option<Utf8PathBuf>,
+    /// Path relative to the repository root
+    file_missing: Option<Utf8PathBuf>,
+    topic: Option<String>,
+    /// Primary language of the repository, compared ignoring case
+    language: Option<String>,
+}
+
+impl Condition {
+    /// Files looked up on the repository
+    pub fn file_paths(&self) -> impl Iterator<Item = &Utf8Path> {
+        self.file_exists
+            .iter()
+            .chain(self.file_missing.iter())
+            .map(|p| p.as_path())
+    }
+
+    pub fn matches_repository(&self, repository: &Repository, directory: &Utf8Path) -> bool {
+        if let Some(file) = &self.file_exists {
+            if !directory.join(file).exists() {
+                return false;
+            }
+        }
+        if let Some(file) = &self.file_missing {
+            if directory.join(file).exists() {
+                return false;
+            }
+        }
+        if let Some(topic) = &self.topic {
+            if !repository.topics.contains(topic) {
+                return false;
+            }
+        }
+        if let Some(language) = &self.language {
+            let matches = repository
+                .language
+                .as_ref()
+                .map(|l| l.eq_ignore_ascii_case(language))
+                .unwrap_or(false);
+            if !matches {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn needs_contents(&self) -> bool {
+        self.contains.is_some() || self.not_contains.is_some()
+    }
+
+    pub fn matches_contents(&self, text: &str) -> bool {
+        if let Some(contains) = &self.contains {
+            if !contains.is_match(text) {
+                return false;
+            }
+        }
+        if let Some(not_contains) = &self.not_contains {
+            if not_contains.is_match(text) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use crate::Repository;
+
+    use super::Condition;
+
+    fn repository() -> Repository {
+        Repository {
+            name: "my-repo".to_string(),
+            private: true,
+            fork: false,
+            archived: false,
+            ssh_url: "any-url".to_string(),
+            clone_url: "https://any-url".to_string(),
+            default_branch: "main".to_string(),
+            language: Some("Python".to_string()),
+            topics: vec!["backend".to_string()],
+            permissions: None,
+        }
+    }
+
+    #[test]
+    fn test_contents() {
+        let condition: Condition = toml::from_str(r#"not_contains = "^import requests$""#).unwrap();
+        assert!(condition.needs_contents());
+        assert!(condition.matches_contents("import os\n"));
+        assert!(!condition.matches_contents("import requests"));
+
+        let condition: Condition = toml::from_str(r#"contains = "requests""#).unwrap();
+        assert!(condition.matches_contents("import requests"));
+        assert!(!condition.matches_contents("import os"));
+    }
+
+    #[test]
+    fn test_repository() {
+        let directory = Utf8Path::new(env!("CARGO_MANIFEST_DIR"));
+        let condition: Condition = toml::from_str(
+            r#"
+            file_exists = "Cargo.toml"
+            file_missing = "pyproject.toml"
+            topic = "backend"
+            language = "python"
+            "#,
+        )
+        .unwrap();
+        assert!(!condition.needs_contents());
+        assert!(condition.matches_repository(&repository(), directory));
+
+        let condition: Condition = toml::from_str(r#"file_exists = "setup.py""#).unwrap();
+        assert!(!condition.matches_repository(&repository(), directory));
+
+        let condition: Condition = toml::from_str(r#"topic = "frontend""#).unwrap();
+        assert!(!condition.matches_repository(&repository(), directory));
+
+        let condition: Condition = toml::from_str(r#"language = "Go""#).unwrap();
+        assert!(!condition.matches_repository(&repository(), directory));
+    }
+}
@@ -0,0 +1,528 @@
+use std::cmp::Ordering;
+
+use camino::Utf8Path;
+use color_eyre::{eyre::eyre, Result};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+/// Characters that make up version operators like `^`, `~=`, `>=` or `==`
+const OPERATORS: &[char] = &['^', '~', '=', '<', '>', '!', ' '];
+
+/// Bumps a dependency on a manifest without touching anything else on it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DependencyProcessor {
+    name: String,
+    /// A plain version keeps the operator already used on the manifest, `^1.2` becomes `^2.0`,
+    /// a version starting with an operator replaces the whole requirement
+    version: String,
+    /// Inferred from the file name when missing
+    ecosystem: Option<Ecosystem>,
+    /// Run the ecosystem tool to update the lockfile when the manifest changes
+    #[serde(default)]
+    update_lockfile: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Ecosystem {
+    Cargo,
+    Npm,
+    Pip,
+    Go,
+    Maven,
+}
+
+impl Ecosystem {
+    pub fn from_path(path: &Utf8Path) -> Option<Self> {
+        let file_name = path.file_name()?;
+        let ecosystem = match file_name {
+            "Cargo.toml" => Self::Cargo,
+            "package.json" => Self::Npm,
+            "pyproject.toml" => Self::Pip,
+            "go.mod" => Self::Go,
+            "pom.xml" => Self::Maven,
+            _ if file_name.starts_with("requirements") && file_name.ends_with(".txt") => Self::Pip,
+            _ => return None,
+        };
+        Some(ecosystem)
+    }
+}
+
+impl DependencyProcessor {
+    pub fn process(&self, file: &Utf8Path, text: &mut String) -> Result<bool> {
+        let ecosystem = self.ecosystem(file)?;
+        let has_lockfile = match ecosystem {
+            Ecosystem::Maven => false,
+            Ecosystem::Pip => file.file_name() == Some("pyproject.toml"),
+            _ => true,
+        };
+        if self.update_lockfile && !has_lockfile {
+            return Err(eyre!(
+                "update_lockfile is not supported on {}, it has no lockfile",
+                file.file_name().unwrap_or_default()
+            ));
+        }
+        let new_text = match ecosystem {
+            Ecosystem::Cargo => bump_toml_tables(text, &self.name, &self.version, |header| {
+                header.ends_with("dependencies")
+            }),
+            Ecosystem::Npm => self.bump_npm(text),
+            Ecosystem::Pip if file.file_name() == Some("pyproject.toml") => {
+                // PEP 621 lists and poetry tables can be on the same file
+                let text = self.bump_pep508(text);
+                bump_toml_tables(&text, &self.name, &self.version, |header| {
+                    header.starts_with("tool.poetry") && header.ends_with("dependencies")
+                })
+            }
+            Ecosystem::Pip => self.bump_pep508(text),
+            Ecosystem::Go => self.bump_go(text),
+            Ecosystem::Maven => self.bump_maven(text),
+        };
+        if new_text == *text {
+            return Ok(false);
+        }
+        *text = new_text;
+        Ok(true)
+    }
+
+    /// Command that refreshes the lockfile next to `file`, if there is one
+    /// Whether a command runs after changing a file, which can touch files anywhere on the repository
+    pub fn has_lockfile_command(&self) -> bool {
+        self.update_lockfile
+    }
+
+    pub fn lockfile_command(&self, file: &Utf8Path) -> Result<Option<Vec<String>>> {
+        if !self.update_lockfile {
+            return Ok(None);
+        }
+        let directory = file.parent().unwrap_or_else(|| Utf8Path::new("."));
+        let command: &[&str] = match self.ecosystem(file)? {
+            Ecosystem::Cargo => &["cargo", "update", "-p", &self.name],
+            Ecosystem::Npm if directory.join("package-lock.json").exists() => {
+                &["npm", "install", "--package-lock-only", "--ignore-scripts"]
+            }
+            Ecosystem::Npm if directory.join("yarn.lock").exists() => {
+                &["yarn", "install", "--mode=update-lockfile"]
+            }
+            Ecosystem::Go => &["go", "mod", "tidy"],
+            Ecosystem::Pip if directory.join("poetry.lock").exists() => &["poetry", "lock"],
+            Ecosystem::Pip if directory.join("uv.lock").exists() => &["uv", "lock"],
+            // Maven and requirements files are refused by `process`
+            Ecosystem::Npm | Ecosystem::Pip | Ecosystem::Maven => return Ok(None),
+        };
+        Ok(Some(command.iter().map(|c| c.to_string()).collect()))
+    }
+
+    fn ecosystem(&self, file: &Utf8Path) -> Result<Ecosystem> {
+        self.ecosystem
+            .or_else(|| Ecosystem::from_path(file))
+            .ok_or_else(|| eyre!("failed to infer the ecosystem of {}", file))
+    }
+
+    fn bump_npm(&self, text: &str) -> String {
+        let re = Regex::new(&format!(
+            r#"("{}"\s*:\s*")([^"]*)(")"#,
+            regex::escape(&self.name)
+        ))
+        .unwrap();
+        re.replace_all(text, |c: &Captures| {
+            let existing = &c[2];
+            // Leave git, file, workspace and tag requirements alone
+            if existing.contains(':') || existing.contains('/') || existing == "*" {
+                return c[0].to_owned();
+            }
+            format!(
+                "{}{}{}",
+                &c[1],
+                bump_requirement(existing, &self.version),
+                &c[3]
+            )
+        })
+        .into_owned()
+    }
+
+    fn bump_pep508(&self, text: &str) -> String {
+        // Names are compared like pip does, ignoring case and treating `-_.` the same
+        let name = self
+            .name
+            .split(['-', '_', '.'])
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join("[-_.]");
+        let re = Regex::new(&format!(
+            r#"(?mi)(^\s*|["'])({}(?:\[[^\]]*\])?\s*)((?:[<>=!~]=?|===)\s*[^\s,;"'#]+(?:\s*,\s*[<>=!~]=?\s*[^\s,;"'#]+)*)"#,
+            name
+        ))
+        .unwrap();
+        re.replace_all(text, |c: &Captures| {
+            format!(
+                "{}{}{}",
+                &c[1],
+                &c[2],
+                bump_requirement(&c[3], &self.version)
+            )
+        })
+        .into_owned()
+    }
+
+    fn bump_go(&self, text: &str) -> String {
+        let version = if self.version.starts_with('v') {
+            self.version.clone()
+        } else {
+            format!("v{}", self.version)
+        };
+        lazy_static! {
+            static ref REQUIRE_BLOCK: Regex =
+                Regex::new(r"(?ms)^\s*require\s*\(.*?^\s*\)").unwrap();
+        }
+        let name = regex::escape(&self.name);
+        let require = Regex::new(&format!(r"(?m)^(\s*require\s+{}\s+)(v\S+)", name)).unwrap();
+        let block_line = Regex::new(&format!(r"(?m)^(\s*{}\s+)(v\S+)", name)).unwrap();
+        // Only requirements change, `exclude` and `replace` keep pointing to their versions
+        let bump = |c: &Captures| format!("{}{}", &c[1], version);
+        let text = require.replace_all(text, bump);
+        REQUIRE_BLOCK
+            .replace_all(&text, |c: &Captures| {
+                block_line.replace_all(&c[0], bump).into_owned()
+            })
+            .into_owned()
+    }
+
+    fn bump_maven(&self, text: &str) -> String {
+        lazy_static! {
+            static ref DEPENDENCY: Regex = Regex::new(r"(?s)<dependency>.*?</dependency>").unwrap();
+            static ref VERSION: Regex =
+                Regex::new(r"(<version>\s*)([^<]*?)(\s*</version>)").unwrap();
+            static ref PROPERTY: Regex = Regex::new(r"^\$\{(.+)\}$").unwrap();
+        }
+        // Either `artifactId` or `groupId:artifactId`
+        let (group, artifact) = match self.name.split_once(':') {
+            Some((group, artifact)) => (Some(group), artifact),
+            None => (None, self.name.as_str()),
+        };
+        let artifact_tag = format!("<artifactId>{}</artifactId>", artifact);
+        let group_tag = group.map(|g| format!("<groupId>{}</groupId>", g));
+
+        let mut properties = vec![];
+        let text = DEPENDENCY.replace_all(text, |c: &Captures| {
+            let dependency = &c[0];
+            let matches = dependency.contains(&artifact_tag)
+                && group_tag
+                    .as_ref()
+                    .map(|g| dependency.contains(g))
+                    .unwrap_or(true);
+            if !matches {
+                return dependency.to_owned();
+            }
+            VERSION
+                .replace(dependency, |v: &Captures| {
+                    // Versions coming from properties are bumped where the property is defined
+                    if let Some(property) = PROPERTY.captures(&v[2]) {
+                        properties.push(property[1].to_owned());
+                        return v[0].to_owned();
+                    }
+                    format!("{}{}{}", &v[1], &self.version, &v[3])
+                })
+                .into_owned()
+        });
+
+        let mut text = text.into_owned();
+        for property in properties {
+            let re = Regex::new(&format!(
+                r"(<{0}>\s*)[^<]*?(\s*</{0}>)",
+                regex::escape(&property)
+            ))
+            .unwrap();
+            text = re
+                .replace_all(&text, |c: &Captures| {
+                    format!("{}{}{}", &c[1], &self.version, &c[2])
+                })
+                .into_owned();
+        }
+        text
+    }
+}
+
+/// Keeps the operator of the current requirement unless `version` brings its own
+fn bump_requirement(existing: &str, version: &str) -> String {
+    if version.starts_with(OPERATORS) {
+        return version.to_owned();
+    }
+    // Other clauses of `>=1.0, <2` are kept, upper bounds are raised when they exclude the version
+    let separator = if existing.contains(", ") { ", " } else { "," };
+    let mut clauses = existing.split(',').map(str::trim);
+    let first = clauses.next().unwrap_or_default();
+    let operator = first
+        .chars()
+        .take_while(|c| OPERATORS.contains(c))
+        .collect::<String>();
+    let mut output = vec![format!("{}{}", operator, version)];
+    for clause in clauses {
+        let operator = clause
+            .chars()
+            .take_while(|c| OPERATORS.contains(c))
+            .collect::<String>();
+        let bound = clause[operator.len()..].trim();
+        let ordering = compare_versions(version, bound);
+        let clause = match operator.trim() {
+            "<" if ordering.is_ge() => format!("<{}", next_major(version, bound)),
+            "<=" if ordering.is_gt() => format!("<={}", version),
+            "!=" if ordering.is_eq() => continue,
+            _ => clause.to_owned(),
+        };
+        output.push(clause);
+    }
+    output.join(separator)
+}
+
+/// Compares the numeric components of two versions, missing components count as 0
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let components = |version: &str| {
+        version
+            .split('.')
+            .map(|c| {
+                c.chars()
+                    .take_while(char::is_ascii_digit)
+                    .collect::<String>()
+                    .parse::<u64>()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>()
+    };
+    let (mut a, mut b) = (components(a), components(b));
+    let length = a.len().max(b.len());
+    a.resize(length, 0);
+    b.resize(length, 0);
+    a.cmp(&b)
+}
+
+/// Major version after `version`, with as many components as `like`, `2.0.1` and `1.0` give `3.0`
+fn next_major(version: &str, like: &str) -> String {
+    let major = version
+        .split('.')
+        .next()
+        .and_then(|m| m.parse::<u64>().ok())
+        .unwrap_or(0);
+    let mut components = vec![(major + 1).to_string()];
+    components.resize(like.split('.').count().max(1), "0".to_owned());
+    components.join(".")
+}
+
+/// Bumps `name = "1.0"`, `name = { version = "1.0" }` and `[section.name]` tables inside of
+/// the TOML tables accepted by `is_dependency_table`
+fn bump_toml_tables(
+    text: &str,
+    name: &str,
+    version: &str,
+    is_dependency_table: impl Fn(&str) -> bool,
+) -> String {
+    lazy_static! {
+        static ref HEADER: Regex = Regex::new(r"^\s*\[+\s*([^\]]+?)\s*\]+").unwrap();
+        static ref VERSION_KEY: Regex = Regex::new(r#"^(\s*version\s*=\s*")([^"]*)(")"#).unwrap();
+    }
+    let name = regex::escape(name);
+    let simple = Regex::new(&format!(r#"^(\s*"?{}"?\s*=\s*")([^"]*)(")"#, name)).unwrap();
+    let inline = Regex::new(&format!(
+        r#"^(\s*"?{}"?\s*=\s*\{{[^}}]*?\bversion\s*=\s*")([^"]*)(")"#,
+        name
+    ))
+    .unwrap();
+    let table_suffix = Regex::new(&format!(r#"\.\s*"?{}"?$"#, name)).unwrap();
+
+    let mut in_dependencies = false;
+    let mut in_dependency_table = false;
+    let mut output = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        if let Some(header) = HEADER.captures(line) {
+            let header = header[1].trim();
+            in_dependencies = is_dependency_table(header);
+            in_dependency_table = table_suffix.is_match(header)
+                && is_dependency_table(table_suffix.replace(header, "").as_ref());
+            output.push_str(line);
+            continue;
+        }
+        let re = match (in_dependencies, in_dependency_table) {
+            (true, _) if inline.is_match(line) => &inline,
+            (true, _) => &simple,
+            (_, true) => &*VERSION_KEY,
+            _ => {
+                output.push_str(line);
+                continue;
+            }
+        };
+        let line = re.replace(line, |c: &Captures| {
+            format!("{}{}{}", &c[1], bump_requirement(&c[2], version), &c[3])
+        });
+        output.push_str(&line);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::{bump_requirement, DependencyProcessor};
+
+    fn bump(file: &str, version: &str, text: &str) -> String {
+        let processor: DependencyProcessor =
+            toml::from_str(&format!("name = \"internal-lib\"\nversion = {:?}", version)).unwrap();
+        let mut text = text.to_owned();
+        processor.process(Utf8Path::new(file), &mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_cargo() {
+        let manifest = r#"[package]
+name = "internal-lib-user"
+version = "0.1.0"
+
+[dependencies]
+internal-lib = "^1.2"
+serde = "1.0"
+
+[dev-dependencies]
+internal-lib = { version = "~1.2", features = ["test"] }
+
+[target.'cfg(unix)'.dependencies.internal-lib]
+version = "1.2"
+"#;
+        assert_eq!(
+            bump("Cargo.toml", "2.0.1", manifest),
+            manifest
+                .replace("\"^1.2\"", "\"^2.0.1\"")
+                .replace("\"~1.2\"", "\"~2.0.1\"")
+                .replace("version = \"1.2\"", "version = \"2.0.1\"")
+        );
+    }
+
+    #[test]
+    fn test_npm() {
+        assert_eq!(
+            bump(
+                "package.json",
+                "2.0.0",
+                r#"{ "dependencies": { "internal-lib": "^1.0.0", "other": "1.0.0" }, "devDependencies": { "internal-lib": "github:org/internal-lib" } }"#
+            ),
+            r#"{ "dependencies": { "internal-lib": "^2.0.0", "other": "1.0.0" }, "devDependencies": { "internal-lib": "github:org/internal-lib" } }"#
+        );
+    }
+
+    #[test]
+    fn test_pip() {
+        assert_eq!(
+            bump(
+                "requirements-dev.txt",
+                "2.0",
+                "Internal_Lib[extra]>=1.0,<2 ; python_version > '3'\ninternal-lib-other==1.0\n"
+            ),
+            "Internal_Lib[extra]>=2.0,<3 ; python_version > '3'\ninternal-lib-other==1.0\n"
+        );
+        assert_eq!(
+            bump(
+                "pyproject.toml",
+                "2.0",
+                "[project]\ndependencies = [\"internal-lib~=1.0\"]\n\n[tool.poetry.dependencies]\ninternal-lib = \"^1.0\"\n"
+            ),
+            "[project]\ndependencies = [\"internal-lib~=2.0\"]\n\n[tool.poetry.dependencies]\ninternal-lib = \"^2.0\"\n"
+        );
+    }
+
+    #[test]
+    fn test_bump_requirement() {
+        assert_eq!(bump_requirement(">=1.0, <2.0", "2.0.1"), ">=2.0.1, <3.0");
+        assert_eq!(bump_requirement(">=1.0,<3", "2.0.1"), ">=2.0.1,<3");
+        assert_eq!(
+            bump_requirement(">=1.0,<=1.5,!=2.0.1", "2.0.1"),
+            ">=2.0.1,<=2.0.1"
+        );
+        assert_eq!(bump_requirement("^1.2", ">=2,<3"), ">=2,<3");
+    }
+
+    #[test]
+    fn test_update_lockfile() {
+        let processor: DependencyProcessor =
+            toml::from_str("name = \"internal-lib\"\nversion = \"2.0\"\nupdate_lockfile = true")
+                .unwrap();
+        let mut text =
+            "<dependency><artifactId>internal-lib</artifactId><version>1.0</version></dependency>"
+                .to_owned();
+        assert!(processor
+            .process(Utf8Path::new("pom.xml"), &mut text)
+            .is_err());
+        let mut text = "internal-lib==1.0\n".to_owned();
+        assert!(processor
+            .process(Utf8Path::new("requirements.txt"), &mut text)
+            .is_err());
+
+        let directory = tempdir::TempDir::new("lockfile").unwrap();
+        let directory = Utf8Path::from_path(directory.path()).unwrap();
+        std::fs::write(directory.join("uv.lock"), "").unwrap();
+        assert_eq!(
+            processor
+                .lockfile_command(&directory.join("pyproject.toml"))
+                .unwrap(),
+            Some(vec!["uv".to_owned(), "lock".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_go() {
+        let processor: DependencyProcessor =
+            toml::from_str("name = \"github.com/org/internal-lib\"\nversion = \"1.4.0\"").unwrap();
+        let mut text = "module x\n\nrequire github.com/org/internal-lib v1.2.0\n\nrequire (\n\tgithub.com/org/internal-lib v1.2.0 // indirect\n\tgithub.com/org/internal-lib-other v1.2.0\n)\n".to_owned();
+        assert!(processor
+            .process(Utf8Path::new("go.mod"), &mut text)
+            .unwrap());
+        assert_eq!(
+            text,
+            "module x\n\nrequire github.com/org/internal-lib v1.4.0\n\nrequire (\n\tgithub.com/org/internal-lib v1.4.0 // indirect\n\tgithub.com/org/internal-lib-other v1.2.0\n)\n"
+        );
+    }
+
+    #[test]
+    fn test_go_exclude_and_replace() {
+        let processor: DependencyProcessor =
+            toml::from_str("name = \"github.com/org/internal-lib\"\nversion = \"1.4.0\"").unwrap();
+        let mut text = "module x\n\nrequire (\n\tgithub.com/org/internal-lib v1.2.0\n)\n\nexclude github.com/org/internal-lib v1.3.0\n\nreplace (\n\tgithub.com/org/internal-lib v1.2.0 => ../internal-lib\n)\n".to_owned();
+        assert!(processor
+            .process(Utf8Path::new("go.mod"), &mut text)
+            .unwrap());
+        assert_eq!(
+            text,
+            "module x\n\nrequire (\n\tgithub.com/org/internal-lib v1.4.0\n)\n\nexclude github.com/org/internal-lib v1.3.0\n\nreplace (\n\tgithub.com/org/internal-lib v1.2.0 => ../internal-lib\n)\n"
+        );
+    }
+
+    #[test]
+    fn test_maven() {
+        let pom = r#"<project>
+  <properties>
+    <internal-lib.version>1.0</internal-lib.version>
+  </properties>
+  <dependencies>
+    <dependency>
+      <groupId>com.org</groupId>
+      <artifactId>internal-lib</artifactId>
+      <version>${internal-lib.version}</version>
+    </dependency>
+    <dependency>
+      <groupId>com.org</groupId>
+      <artifactId>internal-lib-client</artifactId>
+      <version>1.0</version>
+    </dependency>
+  </dependencies>
+</project>
+"#;
+        assert_eq!(
+            bump("pom.xml", "2.0", pom),
+            pom.replace(
+                "<internal-lib.version>1.0</internal-lib.version>",
+                "<internal-lib.version>2.0</internal-lib.version>"
+            )
+        );
+    }
+}
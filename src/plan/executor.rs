@@ -1,34 +1,131 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
+    path::Path,
     process::{Output, Stdio},
     sync::Arc,
+    time::Duration,
 };
 
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use color_eyre::{
     eyre::{eyre, Context},
     Help, Result, SectionExt,
 };
-use tokio::{fs, process::Command};
-use tracing::{debug, info, instrument, trace};
+use fs2::FileExt;
+use ignore::WalkBuilder;
+use tokio::{fs, process::Command, task};
+use tracing::{debug, info, instrument, trace, warn};
 
 use crate::Repository;
 
-use super::{glob_pattern::GlobPattern, FileOperation, Plan};
+use super::{
+    branch::{foreign_commits, BranchStrategy, COMMIT_TRAILER, FOREIGN_COMMITS_FORMAT},
+    checkout,
+    glob_pattern::GlobPattern,
+    template,
+    text_file::TextFile,
+    FileCreation, FileOperation, GitProtocol, IfExists, OnFailure, Patch, Plan,
+};
+
+const FORK_REMOTE: &str = "fork";
+
+/// Shallow clones are deepened this many commits at a time looking for where the branch started,
+/// and fetch the whole history after that many attempts
+const DEEPEN_BY: u32 = 100;
+const DEEPEN_ATTEMPTS: u32 = 5;
+
+/// Touched inside .git after each `git gc`
+const GC_MARKER: &str = "there-i-fixed-it-gc";
+const GC_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Answers git with the user and token from the environment of each command, so the token
+/// is never written to .git/config
+const CREDENTIAL_HELPER: &str = r#"credential.helper=!f() { test "$1" = get && echo "username=${THERE_I_FIXED_IT_GIT_USER}" && echo "password=${THERE_I_FIXED_IT_GIT_TOKEN}"; }; f"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PatchOutcome {
+    Clean,
+    /// Applied with less context lines than the patch has
+    Fuzz,
+    ThreeWay,
+    AlreadyApplied,
+    Failed,
+}
+
+impl Display for PatchOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let outcome = match self {
+            PatchOutcome::Clean => "cleanly",
+            PatchOutcome::Fuzz => "with fuzz",
+            PatchOutcome::ThreeWay => "with a three-way merge",
+            PatchOutcome::AlreadyApplied => "already applied",
+            PatchOutcome::Failed => "not at all",
+        };
+        f.write_str(outcome)
+    }
+}
+
+/// Plans can only write below the repository root
+fn inside_repository(path: &Utf8Path) -> Result<&Utf8Path> {
+    let inside = path
+        .components()
+        .all(|c| matches!(c, Utf8Component::Normal(_) | Utf8Component::CurDir));
+    if !inside {
+        return Err(eyre!(
+            "{} is outside of the repository, paths have to be relative and without ..",
+            path
+        ));
+    }
+    Ok(path)
+}
+
+/// Folder of the worktree under the repository, `/` and `%` of the branch are escaped so
+/// different branches never share it
+fn worktree_name(plan: &Plan) -> String {
+    let branch = plan.branch_name.replace('%', "%25").replace('/', "%2F");
+    format!("{}@{}", plan.id(), branch)
+}
+
+/// The branch as it was on the remote before this run
+pub struct BranchState {
+    /// `None` when the repository will be forked and the fork remote is not known yet
+    remote: Option<&'static str>,
+    /// `None` when the branch was never pushed
+    remote_commit: Option<String>,
+    /// The branch was rebased, so it is pushed even without new changes
+    rebased: bool,
+}
 
 pub struct PlanExecutor {
     plan: Arc<Plan>,
     repository: Repository,
+    /// Bare clone shared by every plan
+    clone_directory: Utf8PathBuf,
+    /// Worktree of the plan branch, where files are changed
     directory: Utf8PathBuf,
 }
 
 impl PlanExecutor {
-    pub fn new(plan: Arc<Plan>, repository: Repository, repositories_folder: &Utf8Path) -> Self {
-        let directory = repositories_folder.join("repos").join(&repository.name);
+    pub fn new(plan: Arc<Plan>, repository: Repository, cache_folder: &Utf8Path) -> Self {
+        // Repositories with the same name on other organizations or hosts do not share a clone
+        let namespace = plan.get_provider().clone_namespace();
+        let clone_directory = cache_folder
+            .join("repos")
+            .join(&namespace)
+            .join(&repository.name);
+        // Each plan and branch has its own worktree, so plans running at the same time do not
+        // clobber each other
+        let directory = cache_folder
+            .join("worktrees")
+            .join(&namespace)
+            .join(&repository.name)
+            .join(worktree_name(&plan));
 
         Self {
             plan,
             repository,
+            clone_directory,
             directory,
         }
     }
@@ -37,116 +134,712 @@ impl PlanExecutor {
         debug!("started");
 
         self.clone_repository().await?;
-        self.ensure_branch().await?;
+        let branch = match self.ensure_branch().await? {
+            Some(branch) => branch,
+            None => return Ok(()),
+        };
 
-        if !self.process_operations().await? {
+        let committed = self.process_operations().await? && self.commit().await?;
+        // Rebased branches are pushed even without new changes
+        if !committed && !branch.rebased {
             return Ok(());
         }
 
-        self.commit().await?;
-        self.push().await?;
-        self.open_pr().await?;
+        let fork = self.fork().await?;
+        self.push(fork.as_ref(), &branch).await?;
+        self.open_pr(fork.is_some()).await?;
         Ok(())
     }
 
-    #[instrument(skip(self))]
+    #[instrument(skip(self), fields(directory = self.clone_directory.as_str()))]
     async fn clone_repository(&self) -> Result<()> {
-        if self.directory.exists() {
-            debug!("Skipping");
-            return Ok(());
+        let url = self.clone_url()?;
+        let _lock = self.lock_clone().await?;
+        if self.clone_directory.join(".git").exists() {
+            info!("replacing clone made before worktrees were used");
+            fs::remove_dir_all(&self.clone_directory).await?;
+        }
+        if self.clone_directory.exists() {
+            debug!("updating existing clone");
+            // The protocol may have changed since the repository was cloned
+            self.clone_git_output(&["remote", "set-url", "origin", url])
+                .await
+                .wrap_err("failed to update remote url")?;
+        } else {
+            let output = self
+                .remote_git()
+                .await?
+                .args(["clone", "--bare"])
+                .args(self.plan.clone.clone_args())
+                .arg(url)
+                .arg(&self.clone_directory)
+                .stdin(Stdio::null())
+                .stderr(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?
+                .wait_with_output()
+                .await?;
+            check_process(&output).wrap_err("failed to clone repository")?;
+            // Worktrees start from origin/<branch> instead of the local branches bare clones make
+            self.clone_git_output(&[
+                "config",
+                "remote.origin.fetch",
+                "+refs/heads/*:refs/remotes/origin/*",
+            ])
+            .await
+            .wrap_err("failed to configure remote")?;
+            fs::write(self.clone_directory.join(GC_MARKER), "").await?;
+            info!("done");
         }
 
-        let output = Command::new("git")
-            .args(&["clone", &self.repository.ssh_url.as_str()])
-            .arg(&self.directory)
-            .stdin(Stdio::null())
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?
-            .wait_with_output()
+        let mut args = vec!["fetch".to_owned(), "--prune".to_owned()];
+        if let Some(depth) = self.plan.clone.depth {
+            args.push(format!("--depth={}", depth));
+        }
+        args.push("origin".to_owned());
+        args.push(self.repository.default_branch.clone());
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        self.run_git_in(&self.clone_directory, self.remote_git().await?, &args)
+            .await
+            .wrap_err("failed to fetch changes")?;
+        // Fetching a single branch only prunes that branch
+        self.run_git_in(
+            &self.clone_directory,
+            self.remote_git().await?,
+            &["remote", "prune", "origin"],
+        )
+        .await
+        .wrap_err("failed to prune deleted branches")?;
+        self.delete_local_branches().await?;
+        self.collect_garbage().await
+    }
+
+    /// Bare clones copy the branches of origin as local ones, which are never updated.
+    /// Clones made before worktrees were used can have them too.
+    async fn delete_local_branches(&self) -> Result<()> {
+        let branches = self
+            .clone_git_output(&[
+                "for-each-ref",
+                "--format=%(refname:lstrip=2)",
+                "refs/heads/",
+            ])
             .await?;
-        check_process(&output).wrap_err("failed to clone repository")?;
-        info!("done");
+        let branches = branches.lines().collect::<Vec<_>>();
+        if branches.is_empty() {
+            return Ok(());
+        }
+        let mut args = vec!["branch", "-D"];
+        args.extend(branches);
+        self.clone_git_output(&args)
+            .await
+            .wrap_err("failed to delete local branches")?;
         Ok(())
     }
 
-    #[instrument(skip(self), fields(directory = self.directory.as_str()))]
-    async fn ensure_branch(&self) -> Result<()> {
-        // git branch --show-current is only on git 2.22+
-        let current_branch = self
-            .git_output(&["rev-parse", "--abbrev-ref", "HEAD"])
-            .await
-            .wrap_err("failed to list branch")?;
-        let current_branch = current_branch.trim();
-        if current_branch == self.plan.branch_name {
-            debug!("branch already checked out");
+    /// Plans running at the same time share the clone, they take turns to fetch, add worktrees,
+    /// change its configuration or collect garbage. The lock is released when the file is dropped.
+    async fn lock_clone(&self) -> Result<std::fs::File> {
+        let path = Utf8PathBuf::from(format!("{}.lock", self.clone_directory));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .await
+            .wrap_err_with(|| format!("failed to open {}", path))?
+            .into_std()
+            .await;
+        task::spawn_blocking(move || file.lock_exclusive().map(|_| file))
+            .await?
+            .wrap_err_with(|| format!("failed to lock {}", path))
+    }
+
+    /// Clones are reused across runs, `git gc` every few days keeps them from growing forever
+    async fn collect_garbage(&self) -> Result<()> {
+        let marker = self.clone_directory.join(GC_MARKER);
+        let due = match fs::metadata(&marker).await.and_then(|m| m.modified()) {
+            Ok(modified) => modified
+                .elapsed()
+                .map(|elapsed| elapsed > GC_INTERVAL)
+                .unwrap_or(true),
+            Err(_) => true,
+        };
+        if !due {
             return Ok(());
         }
+        debug!("collecting garbage");
+        self.clone_git_output(&["gc", "--quiet"])
+            .await
+            .wrap_err("failed to collect garbage")?;
+        fs::write(&marker, "").await?;
+        Ok(())
+    }
+
+    /// `None` when the plan does not use sparse checkouts or can change files in any folder
+    async fn sparse_directories(&self) -> Result<Option<Vec<String>>> {
+        if !self.plan.clone.sparse {
+            return Ok(None);
+        }
+        let mut patches = vec![];
+        for patch in &self.plan.patches {
+            let path = self.plan.directory.join(&patch.path);
+            patches.push(
+                fs::read_to_string(&path)
+                    .await
+                    .wrap_err_with(|| format!("failed to read patch {}", path))?,
+            );
+        }
+        let directories = checkout::sparse_directories(&self.plan, &patches);
+        if directories.is_none() {
+            warn!("the plan can change files in any folder, checking out everything");
+        }
+        Ok(directories)
+    }
+
+    /// Worktrees are reused by later runs of the plan, which may have changed, so the folders are
+    /// set again on every run
+    async fn update_sparse_checkout(&self, directories: Option<&[String]>) -> Result<()> {
+        match directories {
+            Some(directories) => {
+                debug!(?directories, "sparse checkout");
+                self.git_output(&["sparse-checkout", "init", "--cone"])
+                    .await
+                    .wrap_err("failed to enable sparse checkout")?;
+                let mut args = vec!["sparse-checkout", "set"];
+                args.extend(directories.iter().map(String::as_str));
+                // Partial clones download the contents of the new folders
+                self.remote_git_output(&args)
+                    .await
+                    .wrap_err("failed to set sparse checkout folders")?;
+            }
+            None => {
+                let sparse = self
+                    .git_output(&["config", "--bool", "core.sparseCheckout"])
+                    .await
+                    .unwrap_or_default();
+                if sparse.trim() == "true" {
+                    self.remote_git_output(&["sparse-checkout", "disable"])
+                        .await
+                        .wrap_err("failed to disable sparse checkout")?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        self.git_output(&["reset", "--hard"])
+    /// Prepares the worktree of the branch following the branch strategy of the plan, `None`
+    /// when the branch is left alone
+    #[instrument(skip(self), fields(directory = self.directory.as_str()))]
+    async fn ensure_branch(&self) -> Result<Option<BranchState>> {
+        let branch = self.plan.branch_name.as_str();
+        let base = format!("origin/{}", self.repository.default_branch);
+        let _lock = self.lock_clone().await?;
+        if !self.directory.exists() {
+            // Worktrees whose folders were removed still hold their branch until pruned
+            self.clone_git_output(&["worktree", "prune"])
+                .await
+                .wrap_err("failed to prune worktrees")?;
+            // Files are checked out below, once the sparse checkout is set up. HEAD stays detached,
+            // as a branch can only be checked out on one worktree and plans may share branches.
+            self.clone_git_output(&[
+                "worktree",
+                "add",
+                "--no-checkout",
+                "--detach",
+                self.directory.as_str(),
+                &base,
+            ])
             .await
-            .wrap_err("failed to reset branch")?;
-        self.git_output(&["checkout", &self.repository.default_branch])
+            .wrap_err("failed to add worktree")?;
+        } else {
+            // An aborted run may have left a rebase behind, there is nothing to abort otherwise
+            let _ = self.git_output(&["rebase", "--abort"]).await;
+        }
+        let sparse_directories = self.sparse_directories().await?;
+        self.update_sparse_checkout(sparse_directories.as_deref())
+            .await?;
+
+        let remote = self.branch_remote().await;
+        let remote_commit = match remote {
+            Some(remote) => self.fetch_branch(remote).await?,
+            None => None,
+        };
+        let mut state = BranchState {
+            remote,
+            remote_commit: remote_commit.clone(),
+            rebased: false,
+        };
+        let (remote, remote_commit) = match (remote, remote_commit) {
+            (Some(remote), Some(remote_commit)) => (remote, remote_commit),
+            _ => {
+                self.start_branch(&base).await?;
+                return Ok(Some(state));
+            }
+        };
+
+        let tracking = format!("{}/{}", remote, branch);
+        self.deepen_until_merge_base(remote, &base, &tracking)
+            .await?;
+        let foreign = self.foreign_commits(&base, &tracking).await?;
+        match self.plan.branch_update.strategy {
+            BranchStrategy::Regenerate => {
+                if !foreign.is_empty() {
+                    self.report_conflict(
+                        format!(
+                            "regenerating the branch drops {} commits not made by this tool",
+                            foreign.len()
+                        ),
+                        &foreign,
+                    )?;
+                }
+                self.start_branch(&base).await?;
+            }
+            BranchStrategy::KeepEdited if foreign.is_empty() => {
+                self.start_branch(&base).await?;
+            }
+            BranchStrategy::KeepEdited => {
+                info!(commits = ?foreign, "branch has commits not made by this tool, leaving it alone");
+                // Only to tell whether the branch still applies on the default branch
+                self.start_branch(&tracking).await?;
+                if let Some(files) = self.rebase(&base).await? {
+                    self.report_conflict(
+                        format!("branch conflicts with {}", self.repository.default_branch),
+                        &files,
+                    )?;
+                }
+                return Ok(None);
+            }
+            BranchStrategy::Rebase => {
+                self.start_branch(&tracking).await?;
+                if let Some(files) = self.rebase(&base).await? {
+                    self.report_conflict(
+                        format!(
+                            "branch cannot be rebased onto {}",
+                            self.repository.default_branch
+                        ),
+                        &files,
+                    )?;
+                    return Ok(None);
+                }
+                let head = self.git_output(&["rev-parse", "HEAD"]).await?;
+                state.rebased = head.trim() != remote_commit;
+            }
+        }
+        Ok(Some(state))
+    }
+
+    /// Resets the worktree to `start`, dropping whatever a previous run left on it
+    async fn start_branch(&self, start: &str) -> Result<()> {
+        // Partial clones download the contents of the checked out files
+        self.remote_git_output(&["checkout", "-f", "--detach", start])
             .await
-            .wrap_err("failed to checkout default branch")?;
+            .wrap_err("failed to checkout branch")?;
+        self.git_output(&["clean", "-fdq"])
+            .await
+            .wrap_err("failed to remove untracked files")?;
+        debug!("started {} from {}", self.plan.branch_name, start);
+        Ok(())
+    }
 
-        self.git_output(&["pull", "-r"])
+    /// Remote the branch is pushed to, forks are only known once a previous run pushed to them
+    async fn branch_remote(&self) -> Option<&'static str> {
+        let forked = !self.repository.can_push() && self.plan.get_provider().fork_owner().is_some();
+        if !forked {
+            return Some("origin");
+        }
+        self.git_output(&["remote", "get-url", FORK_REMOTE])
             .await
-            .wrap_err("failed to pull changes")?;
+            .ok()
+            .map(|_| FORK_REMOTE)
+    }
 
-        let _ = self
-            .git_output(&["checkout", "-b", self.plan.branch_name.as_str()])
+    /// Fetches the branch from `remote` and returns its commit, `None` when it was never pushed
+    async fn fetch_branch(&self, remote: &str) -> Result<Option<String>> {
+        let branch = self.plan.branch_name.as_str();
+        let output = self
+            .remote_git_output(&[
+                "ls-remote",
+                "--heads",
+                remote,
+                &format!("refs/heads/{}", branch),
+            ])
             .await
-            .wrap_err("failed to checkout new branch")?;
-        debug!("changed to branch {}", self.plan.branch_name);
+            .wrap_err("failed to look up remote branch")?;
+        let commit = match output.split_whitespace().next() {
+            Some(commit) => commit.to_owned(),
+            None => return Ok(None),
+        };
+        // Without --depth, which would cut the branch off from the default branch, only commits
+        // not reachable from the fetched default branch are downloaded
+        let refspec = format!("+refs/heads/{}:refs/remotes/{}/{}", branch, remote, branch);
+        self.run_git_in(
+            &self.clone_directory,
+            self.remote_git().await?,
+            &["fetch", remote, &refspec],
+        )
+        .await
+        .wrap_err("failed to fetch branch")?;
+        Ok(Some(commit))
+    }
+
+    /// Shallow clones may miss the commit the branch started from, which rebases and telling
+    /// commits of the branch apart need
+    async fn deepen_until_merge_base(
+        &self,
+        remote: &str,
+        base: &str,
+        tracking: &str,
+    ) -> Result<()> {
+        let shallow = self
+            .clone_git_output(&["rev-parse", "--is-shallow-repository"])
+            .await?;
+        if shallow.trim() != "true" {
+            return Ok(());
+        }
+        let refspecs = [
+            format!("refs/heads/{}", self.repository.default_branch),
+            format!("refs/heads/{}", self.plan.branch_name),
+        ];
+        for attempt in 0..DEEPEN_ATTEMPTS {
+            if self
+                .git_output(&["merge-base", base, tracking])
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+            debug!(attempt, "deepening shallow clone");
+            for (remote, refspec) in [("origin", &refspecs[0]), (remote, &refspecs[1])] {
+                self.run_git_in(
+                    &self.clone_directory,
+                    self.remote_git().await?,
+                    &["fetch", &format!("--deepen={}", DEEPEN_BY), remote, refspec],
+                )
+                .await
+                .wrap_err("failed to deepen clone")?;
+            }
+        }
+        debug!("fetching the whole history");
+        self.run_git_in(
+            &self.clone_directory,
+            self.remote_git().await?,
+            &["fetch", "--unshallow", "origin"],
+        )
+        .await
+        .wrap_err("failed to unshallow clone")?;
         Ok(())
     }
 
+    async fn foreign_commits(&self, base: &str, tracking: &str) -> Result<Vec<String>> {
+        let log = self
+            .git_output(&[
+                "log",
+                FOREIGN_COMMITS_FORMAT,
+                &format!("{}..{}", base, tracking),
+            ])
+            .await
+            .wrap_err("failed to list branch commits")?;
+        Ok(foreign_commits(&log, &self.plan.git_message))
+    }
+
+    /// Rebases the current branch onto `onto`, returning the conflicted files when it fails
+    async fn rebase(&self, onto: &str) -> Result<Option<Vec<String>>> {
+        let err = match self.remote_git_output(&["rebase", onto]).await {
+            Ok(_) => return Ok(None),
+            Err(err) => err,
+        };
+        let conflicts = self
+            .git_output(&["diff", "--name-only", "--diff-filter=U"])
+            .await
+            .unwrap_or_default();
+        let _ = self.git_output(&["rebase", "--abort"]).await;
+        if conflicts.trim().is_empty() {
+            return Err(err.wrap_err("failed to rebase"));
+        }
+        Ok(Some(conflicts.lines().map(str::to_owned).collect()))
+    }
+
+    fn report_conflict(&self, message: String, details: &[String]) -> Result<()> {
+        match self.plan.branch_update.on_conflict {
+            OnFailure::Warn => {
+                warn!(?details, "{}", message);
+                Ok(())
+            }
+            OnFailure::Error => {
+                let details = details.join("\n");
+                Err(eyre!(message).with_section(move || details.header("Details:")))
+            }
+        }
+    }
+
+    fn clone_url(&self) -> Result<&str> {
+        self.repository_url(&self.repository)
+    }
+
+    fn repository_url<'a>(&self, repository: &'a Repository) -> Result<&'a str> {
+        match self.plan.git_protocol {
+            GitProtocol::Ssh => Ok(&repository.ssh_url),
+            GitProtocol::Https if repository.clone_url.is_empty() => Err(eyre!(
+                "no https url for {}, refresh the repository cache with --skip-repository-cache",
+                repository.name
+            )),
+            GitProtocol::Https => Ok(&repository.clone_url),
+        }
+    }
+
+    /// git with the provider credentials when talking to the remote over https
+    async fn remote_git(&self) -> Result<Command> {
+        let mut command = Command::new("git");
+        if self.plan.git_protocol == GitProtocol::Https {
+            let (user, token) = self.plan.get_provider().git_credentials().await?;
+            command
+                .args(["-c", "credential.helper=", "-c", CREDENTIAL_HELPER])
+                .env("THERE_I_FIXED_IT_GIT_USER", user)
+                .env("THERE_I_FIXED_IT_GIT_TOKEN", token.expose())
+                .env("GIT_TERMINAL_PROMPT", "0");
+        }
+        Ok(command)
+    }
+
     #[instrument(skip(self))]
     async fn git_output(&self, args: &[&str]) -> Result<String> {
-        let output = Command::new("git")
+        self.run_git(Command::new("git"), args).await
+    }
+
+    async fn remote_git_output(&self, args: &[&str]) -> Result<String> {
+        self.run_git(self.remote_git().await?, args).await
+    }
+
+    async fn clone_git_output(&self, args: &[&str]) -> Result<String> {
+        self.run_git_in(&self.clone_directory, Command::new("git"), args)
+            .await
+    }
+
+    async fn run_git(&self, command: Command, args: &[&str]) -> Result<String> {
+        self.run_git_in(&self.directory, command, args).await
+    }
+
+    async fn run_git_in(
+        &self,
+        directory: &Utf8Path,
+        mut command: Command,
+        args: &[&str],
+    ) -> Result<String> {
+        let output = command
             .args(args)
             .stdin(Stdio::null())
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
-            .current_dir(&self.directory)
+            .current_dir(directory)
             .spawn()?
             .wait_with_output()
             .await?;
-        Ok(check_process(&output)?)
+        check_process(&output)
     }
 
     async fn process_operations(&self) -> Result<bool> {
-        let mut files_changed = false;
+        // Patches go first so a failed three-way merge can be reset without losing other changes
+        let mut files_changed = self.apply_patches().await?;
         for operation in &self.plan.file_operations {
             files_changed |= self.process_operation(operation).await?;
         }
+        for creation in &self.plan.file_creations {
+            files_changed |= self.create_file(creation).await?;
+        }
         Ok(files_changed)
     }
 
+    async fn apply_patches(&self) -> Result<bool> {
+        let mut changed = false;
+        for patch in &self.plan.patches {
+            let outcome = self.apply_patch(patch).await?;
+            match (outcome, patch.on_failure) {
+                (PatchOutcome::Failed, OnFailure::Error) => {
+                    return Err(eyre!("failed to apply patch {}", patch.path));
+                }
+                (PatchOutcome::Failed, OnFailure::Warn) => {
+                    warn!(patch = patch.path.as_str(), "patch applied {}", outcome)
+                }
+                _ => info!(patch = patch.path.as_str(), "patch applied {}", outcome),
+            }
+            changed |= matches!(
+                outcome,
+                PatchOutcome::Clean | PatchOutcome::Fuzz | PatchOutcome::ThreeWay
+            );
+        }
+        Ok(changed)
+    }
+
+    #[instrument(skip(self, patch), fields(patch = patch.path.as_str()))]
+    async fn apply_patch(&self, patch: &Patch) -> Result<PatchOutcome> {
+        // git runs inside of the repository so the path has to be absolute
+        let path = fs::canonicalize(self.plan.directory.join(&patch.path))
+            .await
+            .wrap_err_with(|| format!("failed to find patch {}", patch.path))?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| eyre!("patch path {:?} is not valid utf-8", path))?;
+
+        if self.git_output(&["apply", "--check", path]).await.is_ok() {
+            self.git_output(&["apply", path]).await?;
+            return Ok(PatchOutcome::Clean);
+        }
+        if self
+            .git_output(&["apply", "--check", "--reverse", path])
+            .await
+            .is_ok()
+        {
+            return Ok(PatchOutcome::AlreadyApplied);
+        }
+        if self
+            .git_output(&["apply", "--check", "-C1", path])
+            .await
+            .is_ok()
+        {
+            self.git_output(&["apply", "-C1", path]).await?;
+            return Ok(PatchOutcome::Fuzz);
+        }
+        let touched_files = self.git_output(&["apply", "--numstat", path]).await?;
+        match self.git_output(&["apply", "--3way", path]).await {
+            Ok(_) => Ok(PatchOutcome::ThreeWay),
+            Err(err) => {
+                debug!("three-way merge failed: {:?}", err);
+                // Only the files touched by the patch are restored, other changes are kept
+                for file in touched_files
+                    .lines()
+                    .filter_map(|l| l.splitn(3, '\t').nth(2))
+                {
+                    self.restore_file(file)
+                        .await
+                        .wrap_err("failed to restore a file after a conflicting patch")?;
+                }
+                Ok(PatchOutcome::Failed)
+            }
+        }
+    }
+
+    async fn restore_file(&self, file: &str) -> Result<()> {
+        let in_head = format!("HEAD:{}", file);
+        if self.git_output(&["cat-file", "-e", &in_head]).await.is_ok() {
+            self.git_output(&["checkout", "HEAD", "--", file]).await?;
+            return Ok(());
+        }
+        self.git_output(&["rm", "-q", "--cached", "--ignore-unmatch", "--", file])
+            .await?;
+        let path = self.directory.join(file);
+        if path.exists() {
+            fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, creation), fields(path = creation.path.as_str()))]
+    async fn create_file(&self, creation: &FileCreation) -> Result<bool> {
+        let path = self.directory.join(inside_repository(&creation.path)?);
+        if path.exists() && creation.if_exists == IfExists::Skip {
+            trace!("file already exists");
+            return Ok(false);
+        }
+
+        let template = creation.template(&self.plan.directory).await?;
+        let contents = template::render(&template, &self.template_variables())
+            .wrap_err_with(|| format!("failed to render template for {}", creation.path))?;
+        if path.exists() && fs::read(&path).await? == contents.as_bytes() {
+            trace!("file is up to date");
+            return Ok(false);
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(&path, &contents)
+            .await
+            .wrap_err_with(|| format!("failed to write {}", path))?;
+        debug!("file written");
+        Ok(true)
+    }
+
+    fn template_variables(&self) -> HashMap<String, String> {
+        let repository = &self.repository;
+        let mut variables = HashMap::new();
+        variables.insert("repository.name".to_owned(), repository.name.clone());
+        variables.insert(
+            "repository.default_branch".to_owned(),
+            repository.default_branch.clone(),
+        );
+        variables.insert("repository.ssh_url".to_owned(), repository.ssh_url.clone());
+        variables.insert(
+            "repository.private".to_owned(),
+            repository.private.to_string(),
+        );
+        variables.insert("repository.fork".to_owned(), repository.fork.to_string());
+        variables
+    }
+
     async fn process_operation(&self, operation: &FileOperation) -> Result<bool> {
-        let files = self.list_files(&self.directory, &operation.pattern).await?;
+        if !operation
+            .when
+            .matches_repository(&self.repository, &self.directory)
+        {
+            debug!(
+                "skipping {}, repository does not match",
+                operation.pattern.as_str()
+            );
+            return Ok(false);
+        }
+        let files = self.list_files(&self.directory, operation).await?;
         let files = files.iter().map(|f| f.as_path()).collect::<Vec<_>>();
 
         self.process_files(&files, operation).await
     }
 
-    #[instrument(skip(self))]
+    #[instrument(skip(self, operation), fields(glob = operation.pattern.as_str()))]
     async fn list_files(
         &self,
         directory: &Utf8Path,
-        pattern: &GlobPattern,
+        operation: &FileOperation,
     ) -> Result<Vec<Utf8PathBuf>> {
-        let mut output = vec![];
-        let glob_pattern = directory.join(pattern.as_str());
+        let root = directory.to_owned();
+        let exclude = operation.exclude.clone();
+        let walker = WalkBuilder::new(directory)
+            .hidden(false)
+            .parents(false)
+            .ignore(operation.gitignore)
+            .git_ignore(operation.gitignore)
+            .git_exclude(operation.gitignore)
+            .git_global(false)
+            .filter_entry(move |entry| {
+                if entry.file_name() == ".git" {
+                    return false;
+                }
+                // Excluded folders are not even walked
+                match relative_path(&root, entry.path()) {
+                    Some(relative) if entry.depth() > 0 => {
+                        !exclude.iter().any(|e| e.matches_path(relative))
+                    }
+                    _ => true,
+                }
+            })
+            .build();
 
-        for entry in glob::glob(&glob_pattern.as_str())? {
+        let mut output = vec![];
+        for entry in walker {
             let entry = entry?;
-            if !entry.is_file() {
+            if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
                 continue;
             }
-            output.push(Utf8PathBuf::from_path_buf(entry).unwrap());
+            let relative = match relative_path(directory, entry.path()) {
+                Some(relative) => relative,
+                None => continue,
+            };
+            if operation.pattern.matches_path(relative) {
+                output.push(directory.join(relative));
+            }
         }
+        output.sort();
 
         Ok(output)
     }
@@ -162,43 +855,217 @@ impl PlanExecutor {
 
     #[instrument(skip(self, operation))]
     async fn process_file(&self, file: &Utf8Path, operation: &FileOperation) -> Result<bool> {
+        if operation.when.needs_contents() {
+            let matches = match self.read_text(file, operation).await? {
+                Some((text, _)) => operation.when.matches_contents(&text),
+                None => false,
+            };
+            if !matches {
+                trace!("skipping, file does not match");
+                return Ok(false);
+            }
+        }
+        let mut changed = self.run_processors(file, operation).await?;
+
+        if operation.delete {
+            fs::remove_file(file)
+                .await
+                .wrap_err_with(|| format!("failed to delete {}", file))?;
+            debug!("deleted");
+            changed = true;
+        } else if let Some(rename_to) = &operation.rename_to {
+            changed |= self.move_file(file, &operation.pattern, rename_to).await?;
+        }
+
+        trace!("done");
+        Ok(changed)
+    }
+
+    async fn run_processors(&self, file: &Utf8Path, operation: &FileOperation) -> Result<bool> {
+        if operation.processors.is_empty() {
+            return Ok(false);
+        }
         trace!("fixing file");
-        let mut text = fs::read_to_string(file).await?;
+        let (mut text, text_file) = match self.read_text(file, operation).await? {
+            Some(decoded) => decoded,
+            None => return Ok(false),
+        };
         let mut changed = false;
+        let mut post_commands = vec![];
 
         for processor in &operation.processors {
-            changed |= processor.process(&mut text);
+            if !processor
+                .condition()
+                .matches_repository(&self.repository, &self.directory)
+            {
+                continue;
+            }
+            if processor
+                .process(file, &mut text)
+                .wrap_err_with(|| format!("failed to process {}", file))?
+            {
+                changed = true;
+                post_commands.extend(processor.post_command(file)?);
+            }
         }
 
-        if !changed {
-            return Ok(changed);
+        if changed {
+            let bytes = text_file
+                .encode(&text)
+                .wrap_err_with(|| format!("failed to encode {}", file))?;
+            let permissions = fs::metadata(file).await?.permissions();
+            fs::write(file, &bytes).await?;
+            fs::set_permissions(file, permissions).await?;
         }
+        for command in post_commands {
+            let directory = file.parent().unwrap_or(&self.directory);
+            self.run_command(&command, directory).await?;
+        }
+        Ok(changed)
+    }
 
-        fs::write(file, &text).await?;
+    #[instrument(skip(self))]
+    async fn run_command(&self, command: &[String], directory: &Utf8Path) -> Result<()> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| eyre!("cannot run an empty command"))?;
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .current_dir(directory)
+            .spawn()
+            .wrap_err_with(|| format!("failed to run {}", program))?
+            .wait_with_output()
+            .await?;
+        check_process(&output).wrap_err_with(|| format!("failed to run {:?}", command))?;
+        debug!("done");
+        Ok(())
+    }
 
-        trace!("done");
+    /// Reads a file as text, binary files and files that cannot be decoded are `None`
+    async fn read_text(
+        &self,
+        file: &Utf8Path,
+        operation: &FileOperation,
+    ) -> Result<Option<(String, TextFile)>> {
+        let bytes = fs::read(file)
+            .await
+            .wrap_err_with(|| format!("failed to read {}", file))?;
+        let decoded = TextFile::decode(&bytes, operation.encoding);
+        if decoded.is_none() {
+            debug!("skipping {}, it is binary or not valid text", file);
+        }
+        Ok(decoded)
+    }
+
+    async fn move_file(
+        &self,
+        file: &Utf8Path,
+        pattern: &GlobPattern,
+        rename_to: &str,
+    ) -> Result<bool> {
+        let relative = file.strip_prefix(&self.directory)?;
+        let destination = pattern
+            .expand(relative.as_str(), rename_to)
+            .ok_or_else(|| eyre!("{} does not match {}", relative, pattern.as_str()))?;
+        if destination == relative.as_str() {
+            return Ok(false);
+        }
+
+        let destination = self
+            .directory
+            .join(inside_repository(Utf8Path::new(&destination))?);
+        if destination.exists() {
+            return Err(eyre!(
+                "cannot move {} to {}, it already exists",
+                file,
+                destination
+            ));
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(file, &destination)
+            .await
+            .wrap_err_with(|| format!("failed to move {} to {}", file, destination))?;
+        debug!("moved to {}", destination);
         Ok(true)
     }
 
+    /// Returns false when there was nothing to commit
     #[instrument(skip(self))]
-    async fn commit(&self) -> Result<()> {
+    async fn commit(&self) -> Result<bool> {
         debug!("committing");
-        let last_commit = self.git_output(&["log", "--format=%B", "-n", "1"]).await?;
-        if last_commit.starts_with(&format!("{}\n", &self.plan.git_message)) {
-            debug!("commit already done");
-            return Ok(());
+        // commit -a would miss new, moved and deleted files
+        self.git_output(&["add", "-A"])
+            .await
+            .wrap_err("failed to stage changes")?;
+        if self
+            .git_output(&["diff", "--cached", "--quiet"])
+            .await
+            .is_ok()
+        {
+            debug!("nothing to commit");
+            return Ok(false);
         }
-        self.git_output(&["commit", "-a", "-m", &self.plan.git_message])
+        self.git_output(&["commit", "-m", &self.plan.git_message, "-m", COMMIT_TRAILER])
             .await
             .wrap_err("failed to commit changes")?;
-        Ok(())
+        Ok(true)
     }
 
+    /// Forks the repository when it cannot be pushed to and the provider knows where to fork it
     #[instrument(skip(self))]
-    async fn push(&self) -> Result<()> {
+    async fn fork(&self) -> Result<Option<Repository>> {
+        let provider = self.plan.get_provider();
+        if self.repository.can_push() || provider.fork_owner().is_none() {
+            return Ok(None);
+        }
+        info!("no push access, forking");
+        let fork = provider
+            .fork_repository(&self.repository.name)
+            .await
+            .wrap_err("failed to fork repository")?;
+        Ok(Some(fork))
+    }
+
+    #[instrument(skip(self, fork, branch))]
+    async fn push(&self, fork: Option<&Repository>, branch: &BranchState) -> Result<()> {
         debug!("pushing");
+        let remote = match fork {
+            Some(fork) => {
+                let url = self.repository_url(fork)?;
+                let _lock = self.lock_clone().await?;
+                // set-url fails when the remote is missing and add when it exists
+                if self
+                    .git_output(&["remote", "set-url", FORK_REMOTE, url])
+                    .await
+                    .is_err()
+                {
+                    self.git_output(&["remote", "add", FORK_REMOTE, url])
+                        .await
+                        .wrap_err("failed to add fork remote")?;
+                }
+                FORK_REMOTE
+            }
+            None => "origin",
+        };
+        // Other strategies keep commits pushed by others, so pushes fail if the branch moved since
+        // it was fetched instead of dropping them
+        let force = match (self.plan.branch_update.strategy, branch.remote) {
+            (BranchStrategy::Regenerate, _) => "-f".to_owned(),
+            (_, Some(branch_remote)) if branch_remote == remote => format!(
+                "--force-with-lease=refs/heads/{}:{}",
+                self.plan.branch_name,
+                branch.remote_commit.as_deref().unwrap_or_default()
+            ),
+            _ => "-f".to_owned(),
+        };
+        let refspec = format!("HEAD:refs/heads/{}", self.plan.branch_name);
         let output = self
-            .git_output(&["push", "-u", "-f", "origin", &self.plan.branch_name])
+            .remote_git_output(&["push", &force, remote, &refspec])
             .await
             .wrap_err("failed to push changes")?;
         trace!("git: {:?}", output);
@@ -206,30 +1073,38 @@ impl PlanExecutor {
     }
 
     #[instrument(skip(self))]
-    async fn open_pr(&self) -> Result<()> {
-        if self
-            .plan
-            .get_provider()
-            .is_pr_open(&self.repository.name, &self.plan.branch_name)
+    async fn open_pr(&self, from_fork: bool) -> Result<()> {
+        let provider = self.plan.get_provider();
+        let fork_owner = if from_fork {
+            provider.fork_owner()
+        } else {
+            None
+        };
+        if provider
+            .is_pr_open(&self.repository.name, &self.plan.branch_name, fork_owner)
             .await?
         {
             info!("pr already opened");
             return Ok(());
         }
 
-        let body = self.plan.pull_request_body.as_ref().map(|b| b.as_str());
+        let body = self.plan.pull_request_body.as_deref();
         let title = self
             .plan
             .pull_request_title
             .as_ref()
-            .unwrap_or(&&self.plan.git_message);
+            .unwrap_or(&self.plan.git_message);
 
-        self.plan
-            .get_provider()
+        // Pull requests from forks name the branch as `owner:branch`
+        let head = match fork_owner {
+            Some(owner) => format!("{}:{}", owner, self.plan.branch_name),
+            None => self.plan.branch_name.clone(),
+        };
+        provider
             .open_pr(
                 &self.repository.name,
                 &self.repository.default_branch,
-                &self.plan.branch_name,
+                &head,
                 title.as_str(),
                 body,
             )
@@ -245,6 +1120,10 @@ impl Display for PlanExecutor {
     }
 }
 
+fn relative_path<'a>(directory: &Utf8Path, path: &'a Path) -> Option<&'a Utf8Path> {
+    Utf8Path::from_path(path)?.strip_prefix(directory).ok()
+}
+
 fn check_process(output: &Output) -> Result<String> {
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -267,11 +1146,14 @@ mod tests {
 
     use camino::{Utf8Path, Utf8PathBuf};
     use tempdir::TempDir;
-    use tokio::process::Command;
+    use tokio::{io::AsyncWriteExt, process::Command};
 
-    use crate::{plan::plan_from_file, Repository};
+    use crate::{
+        plan::{plan_from_file, plan_from_str, Plan},
+        Repository,
+    };
 
-    use super::PlanExecutor;
+    use super::{inside_repository, PlanExecutor, CREDENTIAL_HELPER};
     use crate::plan::executor::check_process;
 
     #[tokio::test]
@@ -280,7 +1162,11 @@ mod tests {
         let plan_file = Utf8PathBuf::from("tests/fixtures/simple-plan.toml");
         let plan = Arc::new(plan_from_file(&plan_file).await.unwrap());
 
-        let repositories = plan.get_provider().list_repositories(false).await.unwrap();
+        let repositories = plan
+            .get_provider()
+            .list_repositories(std::time::Duration::from_secs(0))
+            .await
+            .unwrap();
         assert_eq!(repositories.len(), 1);
 
         for repository in repositories {
@@ -288,9 +1174,438 @@ mod tests {
             let path = Utf8Path::from_path(temp.path()).unwrap();
             let executor = PlanExecutor::new(plan.clone(), repository, path);
             executor.process().await.unwrap();
+            // Runs again on the existing clone, which is fetched instead of cloned
+            executor.process().await.unwrap();
+            assert!(path
+                .join("repos/test/localhost/test-organization/working-repo/HEAD")
+                .exists());
+            assert!(path
+                .join("worktrees/test/localhost/test-organization/working-repo")
+                .join(format!("{}@test/.git", plan.id()))
+                .exists());
+
+            let codeowners = Command::new("git")
+                .args(["show", "test:CODEOWNERS"])
+                .current_dir(temp.path().join("destination.git"))
+                .output()
+                .await
+                .unwrap();
+            assert_eq!(
+                check_process(&codeowners).unwrap(),
+                "* @working-repo-owners\n"
+            );
+
+            let files = Command::new("git")
+                .args(["ls-tree", "-r", "--name-only", "test"])
+                .current_dir(temp.path().join("destination.git"))
+                .output()
+                .await
+                .unwrap();
+            let files = check_process(&files).unwrap();
+            let files = files.lines().collect::<Vec<_>>();
+            assert!(files.contains(&"docs/index.md"));
+            assert!(!files.contains(&"docs/index.rst"));
+            assert!(!files.contains(&".travis.yml"));
+            assert!(files.contains(&"README.md"));
+
+            let vendored = Command::new("git")
+                .args(["show", "test:vendor/lib.py"])
+                .current_dir(temp.path().join("destination.git"))
+                .output()
+                .await
+                .unwrap();
+            assert_eq!(check_process(&vendored).unwrap(), "enabled = True\n");
         }
     }
 
+    #[tokio::test]
+    async fn test_patch_on_failure() {
+        crate::setup_error_handlers().ok();
+        let plan = |on_failure: &str| {
+            let plan = plan_from_str(&format!(
+                r#"
+                branch_name = "test"
+                git_message = "chore: Changes"
+                repositories = ["*"]
+                provider = {{ name = "test" }}
+
+                [[patches]]
+                path = "tests/fixtures/patches/conflicting.patch"
+                on_failure = "{}"
+
+                [[create_files]]
+                path = "created.txt"
+                contents = "created"
+                "#,
+                on_failure
+            ))
+            .unwrap();
+            Arc::new(plan)
+        };
+        let error = plan("error");
+        let repositories = error
+            .get_provider()
+            .list_repositories(std::time::Duration::from_secs(0))
+            .await
+            .unwrap();
+        let (repository, temp) =
+            create_fake_repository(repositories.into_iter().next().unwrap()).await;
+        let path = Utf8Path::from_path(temp.path()).unwrap();
+        let destination = path.join("destination.git");
+        let ssh_url = repository.ssh_url.clone();
+
+        let err = PlanExecutor::new(error, repository, path)
+            .process()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("conflicting.patch"));
+        let branches = git(&destination, &["branch", "--list", "test"]).await;
+        assert!(branches.is_empty());
+
+        let warn = plan("warn");
+        let repositories = warn
+            .get_provider()
+            .list_repositories(std::time::Duration::from_secs(0))
+            .await
+            .unwrap();
+        let repository = Repository {
+            ssh_url,
+            ..repositories.into_iter().next().unwrap()
+        };
+        PlanExecutor::new(warn, repository, path)
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(
+            git(&destination, &["show", "test:created.txt"]).await,
+            "created"
+        );
+        assert_eq!(
+            git(&destination, &["show", "test:file.py"]).await,
+            "enabled = True\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_clone() {
+        crate::setup_error_handlers().ok();
+        let plan = plan_from_str(
+            r#"
+            branch_name = "test"
+            git_message = "chore: Changes"
+            repositories = ["*"]
+            provider = { name = "test" }
+            clone = { depth = 1, blobless = true, sparse = true }
+
+            [[files]]
+            glob = "docs/*.rst"
+            rename_to = "docs/$1.md"
+            "#,
+        )
+        .unwrap();
+        let plan = Arc::new(plan);
+        let repositories = plan
+            .get_provider()
+            .list_repositories(std::time::Duration::from_secs(0))
+            .await
+            .unwrap();
+        let (repository, temp) =
+            create_fake_repository(repositories.into_iter().next().unwrap()).await;
+        let destination = temp.path().join("destination.git");
+        let output = Command::new("git")
+            .args(["config", "uploadpack.allowFilter", "true"])
+            .current_dir(&destination)
+            .output()
+            .await
+            .unwrap();
+        check_process(&output).unwrap();
+        // Local paths ignore --depth and --filter
+        let repository = Repository {
+            ssh_url: format!("file://{}", repository.ssh_url),
+            ..repository
+        };
+
+        let path = Utf8Path::from_path(temp.path()).unwrap();
+        let executor = PlanExecutor::new(plan, repository, path);
+        executor.process().await.unwrap();
+        executor.process().await.unwrap();
+
+        let clone = path.join("repos/test/localhost/test-organization/working-repo");
+        assert!(clone.join("shallow").exists());
+        let worktree =
+            path.join("worktrees/test/localhost/test-organization/working-repo/plan@test");
+        assert!(worktree.join("docs/index.md").exists());
+        assert!(!worktree.join("vendor").exists());
+
+        let files = Command::new("git")
+            .args(["ls-tree", "-r", "--name-only", "test"])
+            .current_dir(&destination)
+            .output()
+            .await
+            .unwrap();
+        let files = check_process(&files).unwrap();
+        let files = files.lines().collect::<Vec<_>>();
+        assert!(files.contains(&"docs/index.md"));
+        assert!(!files.contains(&"docs/index.rst"));
+        // Folders left out of the checkout are still committed
+        assert!(files.contains(&"vendor/lib.py"));
+    }
+
+    #[tokio::test]
+    async fn test_worktree_per_branch() {
+        crate::setup_error_handlers().ok();
+        let plan = |branch_name: &str, plan_path: Option<&str>| {
+            let mut plan = plan_from_str(&format!(
+                r#"
+                branch_name = "{}"
+                git_message = "chore: Changes"
+                repositories = ["*"]
+                provider = {{ name = "test" }}
+
+                [[create_files]]
+                path = "{}.txt"
+                contents = "created"
+                "#,
+                branch_name,
+                branch_name.replace('/', "-")
+            ))
+            .unwrap();
+            plan.path = plan_path.map(Utf8PathBuf::from);
+            Arc::new(plan)
+        };
+        let first = plan("first", None);
+        let repositories = first
+            .get_provider()
+            .list_repositories(std::time::Duration::from_secs(0))
+            .await
+            .unwrap();
+        let (repository, temp) =
+            create_fake_repository(repositories.into_iter().next().unwrap()).await;
+        let path = Utf8Path::from_path(temp.path()).unwrap();
+        let worktrees = path.join("worktrees/test/localhost/test-organization/working-repo");
+        let ssh_url = repository.ssh_url.clone();
+        let executor = |plan: Arc<Plan>| async {
+            let repositories = plan
+                .get_provider()
+                .list_repositories(std::time::Duration::from_secs(0))
+                .await
+                .unwrap();
+            let repository = Repository {
+                ssh_url: ssh_url.clone(),
+                ..repositories.into_iter().next().unwrap()
+            };
+            PlanExecutor::new(plan, repository, path)
+        };
+
+        let first_executor = executor(first).await;
+        first_executor.process().await.unwrap();
+        // Left behind by an aborted run
+        std::fs::write(worktrees.join("plan@first/leftover.txt"), "").unwrap();
+        first_executor.process().await.unwrap();
+        assert!(!worktrees.join("plan@first/leftover.txt").exists());
+
+        // Plans sharing the clone run at the same time, even with the same branch
+        let second = executor(plan("automated/second", None)).await;
+        let third = executor(plan("automated-second", None)).await;
+        let other = executor(plan("first", Some("/plans/other.toml"))).await;
+        let (second, third, other) =
+            tokio::join!(second.process(), third.process(), other.process());
+        second.unwrap();
+        third.unwrap();
+        other.unwrap();
+
+        assert!(worktrees.join("plan@first/first.txt").exists());
+        assert!(worktrees
+            .join("plan@automated%2Fsecond/automated-second.txt")
+            .exists());
+        assert!(!worktrees.join("plan@automated%2Fsecond/first.txt").exists());
+        assert!(worktrees.join("plan@automated-second").exists());
+        let other_worktree = worktrees.join(format!(
+            "{}@first",
+            plan("first", Some("/plans/other.toml")).id()
+        ));
+        assert!(other_worktree.as_str().contains("/other-"));
+        assert!(other_worktree.join("first.txt").exists());
+
+        let files = Command::new("git")
+            .args(["ls-tree", "-r", "--name-only", "first"])
+            .current_dir(temp.path().join("destination.git"))
+            .output()
+            .await
+            .unwrap();
+        assert!(!check_process(&files).unwrap().contains("leftover.txt"));
+
+        // Branches deleted on the remote go away from the clone on the next fetch
+        let clone = path.join("repos/test/localhost/test-organization/working-repo");
+        git(
+            &path.join("destination.git"),
+            &["branch", "-D", "automated-second"],
+        )
+        .await;
+        executor(plan("first", None)).await.process().await.unwrap();
+        let refs = git(&clone, &["for-each-ref", "--format=%(refname)"]).await;
+        assert!(refs.contains("refs/remotes/origin/first"));
+        assert!(!refs.contains("automated-second"));
+        // Only the branches of origin are kept, local ones would never be updated
+        assert!(refs.lines().all(|r| r.starts_with("refs/remotes/origin/")));
+    }
+
+    #[tokio::test]
+    async fn test_branch_strategies() {
+        crate::setup_error_handlers().ok();
+        let plan = |strategy: &str, on_conflict: &str| {
+            let plan = plan_from_str(&format!(
+                r#"
+                branch_name = "test"
+                git_message = "chore: Changes"
+                repositories = ["*"]
+                provider = {{ name = "test" }}
+                branch_update = {{ strategy = "{}", on_conflict = "{}" }}
+
+                [[create_files]]
+                path = "created.txt"
+                contents = "created"
+                "#,
+                strategy, on_conflict
+            ))
+            .unwrap();
+            Arc::new(plan)
+        };
+        let regenerate = plan("regenerate", "error");
+        let repositories = regenerate
+            .get_provider()
+            .list_repositories(std::time::Duration::from_secs(0))
+            .await
+            .unwrap();
+        let (repository, temp) =
+            create_fake_repository(repositories.into_iter().next().unwrap()).await;
+        let path = Utf8Path::from_path(temp.path()).unwrap();
+        let setup = path.join("setup");
+        let destination = path.join("destination.git");
+        let ssh_url = repository.ssh_url;
+        let executor = |plan: Arc<Plan>| async {
+            let repositories = plan
+                .get_provider()
+                .list_repositories(std::time::Duration::from_secs(0))
+                .await
+                .unwrap();
+            let repository = Repository {
+                ssh_url: ssh_url.clone(),
+                ..repositories.into_iter().next().unwrap()
+            };
+            PlanExecutor::new(plan, repository, path)
+        };
+        executor(regenerate.clone()).await.process().await.unwrap();
+
+        // A reviewer pushes to the branch while the default branch moves on
+        git(&setup, &["fetch", "origin"]).await;
+        git(&setup, &["checkout", "-b", "test", "origin/test"]).await;
+        std::fs::write(setup.join("reviewer.txt"), "reviewed").unwrap();
+        git(&setup, &["add", "reviewer.txt"]).await;
+        git(&setup, &["commit", "-m", "Fix typo"]).await;
+        git(&setup, &["push", "origin", "test"]).await;
+        git(&setup, &["checkout", "main"]).await;
+        std::fs::write(setup.join("main.txt"), "main").unwrap();
+        git(&setup, &["add", "main.txt"]).await;
+        git(&setup, &["commit", "-m", "Move main on"]).await;
+        git(&setup, &["push", "origin", "main"]).await;
+
+        executor(plan("rebase", "error"))
+            .await
+            .process()
+            .await
+            .unwrap();
+        let files = git(&destination, &["ls-tree", "-r", "--name-only", "test"]).await;
+        assert!(files.contains("reviewer.txt"));
+        assert!(files.contains("main.txt"));
+        assert!(files.contains("created.txt"));
+        let log = git(&destination, &["log", "--format=%s", "main..test"]).await;
+        assert_eq!(
+            log.lines().collect::<Vec<_>>(),
+            vec!["Fix typo", "chore: Changes"]
+        );
+
+        let rebased = git(&destination, &["rev-parse", "test"]).await;
+        std::fs::write(setup.join("other.txt"), "other").unwrap();
+        git(&setup, &["add", "other.txt"]).await;
+        git(&setup, &["commit", "-m", "Move main on again"]).await;
+        git(&setup, &["push", "origin", "main"]).await;
+        executor(plan("keep_edited", "error"))
+            .await
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(git(&destination, &["rev-parse", "test"]).await, rebased);
+
+        let err = executor(regenerate).await.process().await.unwrap_err();
+        assert!(err.to_string().contains("not made by this tool"));
+        assert_eq!(git(&destination, &["rev-parse", "test"]).await, rebased);
+
+        // The default branch adds the same file as the reviewer with other contents
+        std::fs::write(setup.join("reviewer.txt"), "conflicting").unwrap();
+        git(&setup, &["add", "reviewer.txt"]).await;
+        git(&setup, &["commit", "-m", "Conflict"]).await;
+        git(&setup, &["push", "origin", "main"]).await;
+        let err = executor(plan("rebase", "error"))
+            .await
+            .process()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot be rebased"));
+        assert_eq!(git(&destination, &["rev-parse", "test"]).await, rebased);
+        // Warnings leave the branch alone
+        executor(plan("rebase", "warn"))
+            .await
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(git(&destination, &["rev-parse", "test"]).await, rebased);
+    }
+
+    #[test]
+    fn test_inside_repository() {
+        assert!(inside_repository(Utf8Path::new(".github/CODEOWNERS")).is_ok());
+        assert!(inside_repository(Utf8Path::new("./docs/index.md")).is_ok());
+        assert!(inside_repository(Utf8Path::new("/etc/passwd")).is_err());
+        assert!(inside_repository(Utf8Path::new("docs/../../outside")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_credential_helper() {
+        let mut child = Command::new("git")
+            .args(["-c", "credential.helper=", "-c", CREDENTIAL_HELPER])
+            .args(["credential", "fill"])
+            .env("THERE_I_FIXED_IT_GIT_USER", "x-access-token")
+            .env("THERE_I_FIXED_IT_GIT_TOKEN", "bebacafe")
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"protocol=https\nhost=github.com\n\n")
+            .await
+            .unwrap();
+        let output = check_process(&child.wait_with_output().await.unwrap()).unwrap();
+
+        assert!(output.contains("username=x-access-token\n"));
+        assert!(output.contains("password=bebacafe\n"));
+    }
+
+    async fn git(directory: &Utf8Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(directory)
+            .output()
+            .await
+            .unwrap();
+        check_process(&output).unwrap()
+    }
+
     async fn create_fake_repository(repository: Repository) -> (Repository, TempDir) {
         let temp = TempDir::new("fake-repository").unwrap();
         let setup = Utf8PathBuf::from("tests/create-test-repository.sh");
@@ -304,7 +1619,7 @@ mod tests {
         let command = Command::new(bash_command)
             .arg("-x")
             .arg(&setup)
-            .arg(&temp.path())
+            .arg(temp.path())
             .stdin(Stdio::null())
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
@@ -1,6 +1,9 @@
+use camino::Utf8Path;
+use glob::MatchOptions;
+use regex::Regex;
 use serde::{de::Visitor, Deserialize, Deserializer};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GlobPattern(glob::Pattern);
 
 impl GlobPattern {
@@ -10,9 +13,72 @@ impl GlobPattern {
     pub fn matches(&self, name: &str) -> bool {
         self.0.matches(name)
     }
+    /// Matches a path relative to the repository root, `*` does not cross folders just like when globbing
+    pub fn matches_path(&self, path: &Utf8Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.0.matches_with(path.as_str(), options)
+    }
+
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+
+    /// Builds a regex where every wildcard is a capture group, so `docs/*.rst` can be
+    /// moved to `docs/$1.md`. `**/` captures the directories without the trailing slash.
+    pub fn to_regex(&self) -> Regex {
+        let mut output = String::from("^");
+        let mut chars = self.as_str().chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        output.push_str("(?:(.*)/)?");
+                    } else {
+                        output.push_str("(.*)");
+                    }
+                }
+                '*' => output.push_str("([^/]*)"),
+                '?' => output.push_str("([^/])"),
+                '[' => {
+                    output.push_str("([");
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        output.push('^');
+                    }
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                        if c == '\\' || c == '[' {
+                            output.push('\\');
+                        }
+                        output.push(c);
+                    }
+                    output.push_str("])");
+                }
+                c => output.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        output.push('$');
+        Regex::new(&output).expect("glob patterns always translate to valid regexes")
+    }
+
+    /// Replaces `$1`, `${2}`, ... in `destination` with what each wildcard matched in `path`
+    pub fn expand(&self, path: &str, destination: &str) -> Option<String> {
+        let captures = self.to_regex().captures(path)?;
+        let mut output = String::new();
+        captures.expand(destination, &mut output);
+        // Empty `**` captures would leave double slashes behind
+        while output.contains("//") {
+            output = output.replace("//", "/");
+        }
+        Some(output.trim_start_matches('/').to_owned())
+    }
 }
 
 struct GlobPatternVisitor;
@@ -27,6 +93,8 @@ impl<'de> Visitor<'de> for GlobPatternVisitor {
     where
         E: serde::de::Error,
     {
+        // Paths are matched relative to the repository root, without `./`
+        let v = v.strip_prefix("./").unwrap_or(v);
         match glob::Pattern::new(v) {
             Ok(glob) => Ok(GlobPattern::new(glob)),
             Err(e) => {
@@ -45,3 +113,50 @@ impl<'de> Deserialize<'de> for GlobPattern {
         deserializer.deserialize_any(GlobPatternVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::GlobPattern;
+
+    fn pattern(glob: &str) -> GlobPattern {
+        GlobPattern::new(glob::Pattern::new(glob).unwrap())
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            pattern("docs/*.rst").expand("docs/index.rst", "docs/$1.md"),
+            Some("docs/index.md".to_owned())
+        );
+        assert_eq!(
+            pattern("docs/**/*.rst").expand("docs/api/client.rst", "manual/$1/${2}.md"),
+            Some("manual/api/client.md".to_owned())
+        );
+        assert_eq!(
+            pattern("docs/**/*.rst").expand("docs/client.rst", "manual/$1/${2}.md"),
+            Some("manual/client.md".to_owned())
+        );
+        assert_eq!(
+            pattern("ci/[!a]?.yml").expand("ci/gh.yml", "$1$2.yaml"),
+            Some("gh.yaml".to_owned())
+        );
+        assert_eq!(pattern("docs/*.rst").expand("src/main.rs", "$1"), None);
+    }
+
+    #[test]
+    fn test_leading_dot_slash() {
+        let pattern: GlobPattern = serde_json::from_str(r#""./src/*.py""#).unwrap();
+        assert_eq!(pattern.as_str(), "src/*.py");
+        assert!(pattern.matches_path(Utf8Path::new("src/setup.py")));
+    }
+
+    #[test]
+    fn test_matches_path() {
+        assert!(pattern("*.py").matches_path(Utf8Path::new("setup.py")));
+        assert!(!pattern("*.py").matches_path(Utf8Path::new("src/setup.py")));
+        assert!(pattern("**/*.py").matches_path(Utf8Path::new("setup.py")));
+        assert!(pattern("**/*.py").matches_path(Utf8Path::new("src/app/setup.py")));
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+
+use camino::Utf8Path;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use tracing::warn;
+
+/// Updates container images on Dockerfiles and on YAML files like docker-compose or kubernetes manifests
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageProcessor {
+    images: Vec<ImageRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageRule {
+    /// Image without tag, `python`, `library/python` and `docker.io/library/python` are the same
+    name: String,
+    tag: Option<String>,
+    /// Pins the image, a digest is dropped when only the tag changes as it would point to the old tag
+    digest: Option<String>,
+}
+
+/// Updates `uses:` references on GitHub Actions workflows
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionProcessor {
+    actions: Vec<ActionRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionRule {
+    /// `owner/repository`, actions inside of a folder of the repository are matched too
+    name: String,
+    /// Tag or branch, kept as a comment when `sha` is set
+    version: String,
+    /// Pins the action to a commit
+    sha: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct ImageReference<'a> {
+    name: &'a str,
+    tag: Option<&'a str>,
+    digest: Option<&'a str>,
+}
+
+impl<'a> ImageReference<'a> {
+    fn parse(reference: &'a str) -> Self {
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest)),
+            None => (reference, None),
+        };
+        let name_start = rest.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let (name, tag) = match rest[name_start..].rfind(':') {
+            Some(i) => (&rest[..name_start + i], Some(&rest[name_start + i + 1..])),
+            None => (rest, None),
+        };
+        Self { name, tag, digest }
+    }
+}
+
+impl ImageProcessor {
+    pub fn process(&self, file: &Utf8Path, text: &mut String) -> bool {
+        let new_text = if is_dockerfile(file) {
+            self.process_dockerfile(text)
+        } else {
+            self.process_yaml(text)
+        };
+        if new_text == *text {
+            return false;
+        }
+        *text = new_text;
+        true
+    }
+
+    fn rule(&self, name: &str) -> Option<&ImageRule> {
+        let name = normalize_image_name(name);
+        self.images
+            .iter()
+            .find(|r| normalize_image_name(&r.name) == name)
+    }
+
+    /// Returns the updated reference, or `None` when no rule matches it
+    fn update_reference(&self, reference: &str) -> Option<String> {
+        let reference = ImageReference::parse(reference);
+        let rule = self.rule(reference.name)?;
+        let tag = rule.tag.as_deref().or(reference.tag);
+        let digest = match (&rule.digest, &rule.tag) {
+            (Some(digest), _) => Some(digest.as_str()),
+            (None, Some(tag)) if reference.tag != Some(tag.as_str()) => None,
+            (None, _) => reference.digest,
+        };
+
+        let mut output = reference.name.to_owned();
+        if let Some(tag) = tag {
+            output.push(':');
+            output.push_str(tag);
+        }
+        if let Some(digest) = digest {
+            output.push('@');
+            output.push_str(digest);
+        }
+        Some(output)
+    }
+
+    fn process_yaml(&self, text: &str) -> String {
+        lazy_static! {
+            static ref IMAGE: Regex =
+                Regex::new(r#"(?m)^(\s*-?\s*image:\s*["']?)([^\s"'#]+)"#).unwrap();
+        }
+        IMAGE
+            .replace_all(text, |c: &Captures| match self.update_reference(&c[2]) {
+                Some(reference) => format!("{}{}", &c[1], reference),
+                None => c[0].to_owned(),
+            })
+            .into_owned()
+    }
+
+    fn process_dockerfile(&self, text: &str) -> String {
+        lazy_static! {
+            static ref FROM: Regex = Regex::new(r"(?mi)^(\s*FROM\s+(?:--\S+\s+)*)(\S+)").unwrap();
+            static ref COPY_FROM: Regex =
+                Regex::new(r"(?mi)^(\s*COPY\s+(?:--\S+\s+)*?--from=)(\S+)").unwrap();
+            static ref ARG: Regex =
+                Regex::new(r#"(?mi)^(\s*ARG\s+)(\w+)(=["']?)([^\s"']*)"#).unwrap();
+            static ref VARIABLE: Regex = Regex::new(r"\$\{?(\w+)\}?").unwrap();
+        }
+
+        // ARGs used on images, others like `ARG BASE=python` are left alone
+        let image_arguments = FROM
+            .captures_iter(text)
+            .chain(COPY_FROM.captures_iter(text))
+            .flat_map(|c| {
+                VARIABLE
+                    .captures_iter(c.get(2).unwrap().as_str())
+                    .map(|v| v[1].to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+
+        // Tags built from ARGs are updated where the ARG is declared
+        let mut arguments = HashMap::new();
+        for from in FROM.captures_iter(text) {
+            let reference = ImageReference::parse(&from[2]);
+            let (tag, rule) = match (reference.tag, self.rule(reference.name)) {
+                (Some(tag), Some(rule)) => (tag, rule),
+                _ => continue,
+            };
+            let (variable, new_tag) = match (VARIABLE.captures(tag), &rule.tag) {
+                (Some(variable), Some(new_tag)) => (variable, new_tag),
+                _ => continue,
+            };
+            let whole = variable.get(0).unwrap();
+            let (prefix, suffix) = (&tag[..whole.start()], &tag[whole.end()..]);
+            if new_tag.len() >= prefix.len() + suffix.len()
+                && new_tag.starts_with(prefix)
+                && new_tag.ends_with(suffix)
+            {
+                let value = &new_tag[prefix.len()..new_tag.len() - suffix.len()];
+                arguments.insert(variable[1].to_owned(), value.to_owned());
+            } else {
+                warn!(
+                    image = reference.name,
+                    tag = new_tag.as_str(),
+                    "tag does not fit {}, the ARG is not changed",
+                    tag
+                );
+            }
+        }
+
+        let text = ARG.replace_all(text, |c: &Captures| {
+            if let Some(value) = arguments.get(&c[2]) {
+                return format!("{}{}{}{}", &c[1], &c[2], &c[3], value);
+            }
+            if !image_arguments.contains(&c[2]) {
+                return c[0].to_owned();
+            }
+            // ARG BASE_IMAGE=python:3.9 used as FROM ${BASE_IMAGE}
+            match self.update_reference(&c[4]) {
+                Some(reference) => format!("{}{}{}{}", &c[1], &c[2], &c[3], reference),
+                None => c[0].to_owned(),
+            }
+        });
+        let update = |c: &Captures| {
+            if c[2].contains('$') {
+                return c[0].to_owned();
+            }
+            match self.update_reference(&c[2]) {
+                Some(reference) => format!("{}{}", &c[1], reference),
+                None => c[0].to_owned(),
+            }
+        };
+        let text = FROM.replace_all(&text, update);
+        COPY_FROM.replace_all(&text, update).into_owned()
+    }
+}
+
+impl ActionProcessor {
+    pub fn process(&self, text: &mut String) -> bool {
+        lazy_static! {
+            static ref USES: Regex = Regex::new(
+                r#"(?m)^(\s*-?\s*uses:\s*)(["']?)([^@\s"']+)@([^\s"'#]+)(["']?)([ \t]*#[^\n]*)?"#
+            )
+            .unwrap();
+        }
+        let new_text = USES.replace_all(text, |c: &Captures| {
+            let action = &c[3];
+            let rule = self.actions.iter().find(|r| {
+                action.eq_ignore_ascii_case(&r.name)
+                    || action
+                        .to_lowercase()
+                        .starts_with(&format!("{}/", r.name.to_lowercase()))
+            });
+            let rule = match rule {
+                Some(rule) => rule,
+                None => return c[0].to_owned(),
+            };
+            match &rule.sha {
+                Some(sha) => format!(
+                    "{}{}{}@{}{} # {}",
+                    &c[1], &c[2], action, sha, &c[5], rule.version
+                ),
+                None => format!(
+                    "{}{}{}@{}{}{}",
+                    &c[1],
+                    &c[2],
+                    action,
+                    rule.version,
+                    &c[5],
+                    c.get(6).map(|m| m.as_str()).unwrap_or_default()
+                ),
+            }
+        });
+        if new_text == *text {
+            return false;
+        }
+        *text = new_text.into_owned();
+        true
+    }
+}
+
+fn is_dockerfile(file: &Utf8Path) -> bool {
+    let file_name = file.file_name().unwrap_or_default().to_lowercase();
+    file_name.starts_with("dockerfile")
+        || file_name.starts_with("containerfile")
+        || file_name.ends_with(".dockerfile")
+}
+
+fn normalize_image_name(name: &str) -> &str {
+    let name = name
+        .strip_prefix("docker.io/")
+        .or_else(|| name.strip_prefix("index.docker.io/"))
+        .unwrap_or(name);
+    name.strip_prefix("library/").unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::{ActionProcessor, ImageProcessor, ImageReference};
+
+    fn image_processor() -> ImageProcessor {
+        toml::from_str(
+            r#"
+            images = [
+                { name = "python", tag = "3.11-slim" },
+                { name = "ghcr.io/org/base", digest = "sha256:abc" },
+            ]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            ImageReference::parse("localhost:5000/org/app:1.0@sha256:abc"),
+            ImageReference {
+                name: "localhost:5000/org/app",
+                tag: Some("1.0"),
+                digest: Some("sha256:abc"),
+            }
+        );
+        assert_eq!(
+            ImageReference::parse("python"),
+            ImageReference {
+                name: "python",
+                tag: None,
+                digest: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_dockerfile() {
+        let mut text = concat!(
+            "ARG PYTHON_VERSION=3.9\n",
+            "FROM --platform=linux/amd64 python:${PYTHON_VERSION}-slim AS build\n",
+            "FROM build AS test\n",
+            "FROM docker.io/library/python:3.9@sha256:old\n",
+            "FROM ghcr.io/org/base:2\n",
+            "COPY --from=python:3.8 /usr/bin/python /usr/bin/python\n",
+            "RUN python -m tool --from=python:3.8\n",
+        )
+        .to_owned();
+
+        assert!(image_processor().process(Utf8Path::new("app/Dockerfile"), &mut text));
+        assert_eq!(
+            text,
+            concat!(
+                "ARG PYTHON_VERSION=3.11\n",
+                "FROM --platform=linux/amd64 python:${PYTHON_VERSION}-slim AS build\n",
+                "FROM build AS test\n",
+                "FROM docker.io/library/python:3.11-slim\n",
+                "FROM ghcr.io/org/base:2@sha256:abc\n",
+                "COPY --from=python:3.11-slim /usr/bin/python /usr/bin/python\n",
+                "RUN python -m tool --from=python:3.8\n",
+            )
+        );
+        assert!(!image_processor().process(Utf8Path::new("app/Dockerfile"), &mut text));
+    }
+
+    #[test]
+    fn test_dockerfile_arguments() {
+        let mut text = concat!(
+            "ARG BASE_IMAGE=python:3.9\n",
+            "ARG TOOL=python\n",
+            "FROM ${BASE_IMAGE}\n",
+            "RUN apt-get install $TOOL\n",
+        )
+        .to_owned();
+
+        assert!(image_processor().process(Utf8Path::new("Dockerfile"), &mut text));
+        assert_eq!(
+            text,
+            concat!(
+                "ARG BASE_IMAGE=python:3.11-slim\n",
+                "ARG TOOL=python\n",
+                "FROM ${BASE_IMAGE}\n",
+                "RUN apt-get install $TOOL\n",
+            )
+        );
+
+        // 3.11-slim does not fit `${PYTHON_VERSION}-alpine`, so the ARG is kept
+        let mut text = "ARG PYTHON_VERSION=3.9\nFROM python:${PYTHON_VERSION}-alpine\n".to_owned();
+        assert!(!image_processor().process(Utf8Path::new("Dockerfile"), &mut text));
+    }
+
+    #[test]
+    fn test_yaml() {
+        let mut text = concat!(
+            "services:\n",
+            "  app:\n",
+            "    image: \"python:3.9\"\n",
+            "containers:\n",
+            "  - image: pythonic:1\n",
+        )
+        .to_owned();
+
+        assert!(image_processor().process(Utf8Path::new("docker-compose.yml"), &mut text));
+        assert_eq!(
+            text,
+            concat!(
+                "services:\n",
+                "  app:\n",
+                "    image: \"python:3.11-slim\"\n",
+                "containers:\n",
+                "  - image: pythonic:1\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_actions() {
+        let processor: ActionProcessor = toml::from_str(
+            r#"
+            actions = [
+                { name = "actions/checkout", version = "v4", sha = "b4ffde65f46336ab88eb53be808477a3936bae11" },
+                { name = "github/codeql-action", version = "v3" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let mut text = concat!(
+            "steps:\n",
+            "  - uses: actions/checkout@v2 # old\n",
+            "  - uses: github/codeql-action/init@v2 # scan\n",
+            "  - uses: actions/setup-python@v2\n",
+        )
+        .to_owned();
+
+        assert!(processor.process(&mut text));
+        assert_eq!(
+            text,
+            concat!(
+                "steps:\n",
+                "  - uses: actions/checkout@b4ffde65f46336ab88eb53be808477a3936bae11 # v4\n",
+                "  - uses: github/codeql-action/init@v3 # scan\n",
+                "  - uses: actions/setup-python@v2\n",
+            )
+        );
+        assert!(!processor.process(&mut text));
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use camino::Utf8Path;
+use chrono::Datelike;
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use tracing::trace;
+
+use super::template;
+
+/// Makes sure files start with a license header, an existing header is replaced in place
+/// so company renames and copyright years are kept up to date without duplicating it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LicenseHeaderProcessor {
+    /// Header without comment markers, `{{ year }}` becomes the current year or
+    /// `2019-<current year>` when the existing header started in 2019
+    header: String,
+    /// Comment marker, inferred from the file extension when missing
+    comment: Option<String>,
+    /// The first comment block of a file is an existing header when it matches this
+    #[serde(default, with = "serde_regex")]
+    detect: Option<Regex>,
+    /// Fixed year instead of the current one
+    year: Option<i32>,
+}
+
+impl LicenseHeaderProcessor {
+    pub fn process(&self, file: &Utf8Path, text: &mut String) -> Result<bool> {
+        lazy_static! {
+            // Lines starting with Copyright or SPDX-License-Identifier after the comment markers,
+            // so comments that only mention licenses are kept
+            static ref DETECT: Regex =
+                Regex::new(r"(?im)^[\s/*#!;-]*(copyright\b|spdx-license-identifier:)").unwrap();
+            static ref YEAR: Regex = Regex::new(r"\b(\d{4})(?:\s*-\s*\d{4})?\b").unwrap();
+        }
+        let comment = match self.comment.as_deref().or_else(|| comment_for(file)) {
+            Some(comment) => comment,
+            None => {
+                trace!(file = file.as_str(), "no comment syntax known for file");
+                return Ok(false);
+            }
+        };
+
+        let lines = text.split('\n').collect::<Vec<_>>();
+        let preamble = count_preamble(&lines);
+        let (block, block_comment) = leading_comment(&lines[preamble..], comment);
+        let detect = self.detect.as_ref().unwrap_or(&DETECT);
+        let header_range = header_lines(&lines[preamble..preamble + block], detect);
+        let has_header = header_range.is_some();
+        // A header sharing a line with the `/*` or `*/` of its block replaces the whole block
+        let whole_block = header_range
+            .as_ref()
+            .map(|range| block_comment && (range.start == 0 || range.end == block))
+            .unwrap_or(false);
+        let range = match header_range {
+            Some(_) if whole_block => 0..block,
+            Some(range) => range,
+            None => 0..0,
+        };
+        let existing = lines[preamble + range.start..preamble + range.end].join("\n");
+
+        let current_year = self.year.unwrap_or_else(|| chrono::Local::now().year());
+        let start_year = YEAR
+            .captures(&existing)
+            .filter(|_| has_header)
+            .and_then(|c| c[1].parse::<i32>().ok())
+            .filter(|year| *year < current_year);
+        let mut variables = HashMap::new();
+        variables.insert(
+            "year".to_owned(),
+            match start_year {
+                Some(start_year) => format!("{}-{}", start_year, current_year),
+                None => current_year.to_string(),
+            },
+        );
+        // Headers in a `/* */` block keep that style
+        let (comment, first, last) = if whole_block {
+            (" *", Some("/*"), Some(" */"))
+        } else if has_header && block_comment {
+            (" *", None, None)
+        } else {
+            (comment, None, None)
+        };
+        let header = first
+            .map(str::to_owned)
+            .into_iter()
+            .chain(
+                template::render(&self.header, &variables)?
+                    .trim_end()
+                    .lines()
+                    .map(|line| {
+                        if line.is_empty() {
+                            comment.to_owned()
+                        } else {
+                            format!("{} {}", comment, line)
+                        }
+                    }),
+            )
+            .chain(last.map(str::to_owned))
+            .collect::<Vec<_>>();
+
+        let mut output = lines[..preamble + range.start].to_vec();
+        output.extend(header.iter().map(String::as_str));
+        if has_header {
+            output.extend(&lines[preamble + range.end..]);
+        } else {
+            let rest = &lines[preamble..];
+            if rest.first().map(|l| !l.is_empty()).unwrap_or(false) {
+                output.push("");
+            }
+            output.extend(rest);
+        }
+        let output = output.join("\n");
+        if output == *text {
+            return Ok(false);
+        }
+        *text = output;
+        Ok(true)
+    }
+}
+
+/// Shebangs and python encoding declarations have to stay on the first lines
+fn count_preamble(lines: &[&str]) -> usize {
+    let mut count = 0;
+    if lines.first().map(|l| l.starts_with("#!")).unwrap_or(false) {
+        count += 1;
+    }
+    if lines
+        .get(count)
+        .map(|l| l.starts_with('#') && (l.contains("coding:") || l.contains("coding=")))
+        .unwrap_or(false)
+    {
+        count += 1;
+    }
+    count
+}
+
+/// Number of lines of the comment starting `lines` and whether it is a `/* */` block, which
+/// languages with `//` comments also use for headers
+fn leading_comment(lines: &[&str], comment: &str) -> (usize, bool) {
+    let starts_block = lines
+        .first()
+        .map(|l| l.trim_start().starts_with("/*"))
+        .unwrap_or(false);
+    if comment == "//" && starts_block {
+        // Code after the end of the block on the same line would be lost when replacing it
+        return match lines.iter().position(|l| l.contains("*/")) {
+            Some(end)
+                if lines[end]
+                    .rsplit("*/")
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .is_empty() =>
+            {
+                (end + 1, true)
+            }
+            _ => (0, false),
+        };
+    }
+    let count = lines
+        .iter()
+        .take_while(|l| l.trim_start().starts_with(comment))
+        .count();
+    (count, false)
+}
+
+/// Lines of the leading comment making up the license header: from the first detected line
+/// on, as long as the lines are detected or mention the license, so other comments are kept
+fn header_lines(lines: &[&str], detect: &Regex) -> Option<Range<usize>> {
+    lazy_static! {
+        static ref LICENSE: Regex =
+            Regex::new(r"(?i)licen[cs]e|all rights reserved|warrant").unwrap();
+    }
+    let is_header = |line: &str| detect.is_match(line) || LICENSE.is_match(line);
+    let is_blank = |line: &str| {
+        line.trim_matches(|c: char| c.is_whitespace() || "/*#!;-".contains(c))
+            .is_empty()
+    };
+    let start = lines.iter().position(|l| detect.is_match(l))?;
+    let mut end = start + 1;
+    while end < lines.len() {
+        if is_header(lines[end]) {
+            end += 1;
+            continue;
+        }
+        // Blank comment lines only belong to the header when it continues after them
+        let blanks = lines[end..].iter().take_while(|l| is_blank(l)).count();
+        match lines.get(end + blanks) {
+            Some(line) if blanks > 0 && is_header(line) => end += blanks,
+            _ => break,
+        }
+    }
+    Some(start..end)
+}
+
+fn comment_for(file: &Utf8Path) -> Option<&'static str> {
+    let file_name = file.file_name()?;
+    if matches!(file_name, "Dockerfile" | "Makefile") {
+        return Some("#");
+    }
+    match file.extension()? {
+        "rs" | "go" | "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" | "java" | "kt" | "kts"
+        | "scala" | "swift" | "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "dart" | "proto" => {
+            Some("//")
+        }
+        "py" | "sh" | "bash" | "zsh" | "rb" | "pl" | "r" | "tf" | "hcl" | "yml" | "yaml"
+        | "toml" => Some("#"),
+        "sql" | "lua" | "hs" => Some("--"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::LicenseHeaderProcessor;
+
+    fn license_header(file: &str, text: &str) -> String {
+        let processor: LicenseHeaderProcessor = toml::from_str(
+            r#"
+            header = "Copyright {{ year }} New Corp\nSPDX-License-Identifier: MIT"
+            year = 2024
+            "#,
+        )
+        .unwrap();
+        let mut text = text.to_owned();
+        processor.process(Utf8Path::new(file), &mut text).unwrap();
+        let mut again = text.clone();
+        assert!(!processor.process(Utf8Path::new(file), &mut again).unwrap());
+        text
+    }
+
+    #[test]
+    fn test_adds_header() {
+        assert_eq!(
+            license_header("main.rs", "fn main() {}\n"),
+            "// Copyright 2024 New Corp\n// SPDX-License-Identifier: MIT\n\nfn main() {}\n"
+        );
+        assert_eq!(
+            license_header(
+                "script.sh",
+                "#!/bin/bash\n# Deploys the service\necho hi\n"
+            ),
+            "#!/bin/bash\n# Copyright 2024 New Corp\n# SPDX-License-Identifier: MIT\n\n# Deploys the service\necho hi\n"
+        );
+    }
+
+    #[test]
+    fn test_updates_existing_header() {
+        assert_eq!(
+            license_header("main.go", "// Copyright 2019 Old Corp\n\npackage main\n"),
+            "// Copyright 2019-2024 New Corp\n// SPDX-License-Identifier: MIT\n\npackage main\n"
+        );
+        assert_eq!(
+            license_header(
+                "app.py",
+                "# -*- coding: utf-8 -*-\n# Copyright 2024 Old Corp\nimport os\n"
+            ),
+            "# -*- coding: utf-8 -*-\n# Copyright 2024 New Corp\n# SPDX-License-Identifier: MIT\nimport os\n"
+        );
+    }
+
+    #[test]
+    fn test_keeps_comments_after_header() {
+        assert_eq!(
+            license_header("app.py", "# Copyright 2020 X\n# Module docs\nimport os"),
+            "# Copyright 2020-2024 New Corp\n# SPDX-License-Identifier: MIT\n# Module docs\nimport os"
+        );
+        assert_eq!(
+            license_header(
+                "main.go",
+                "/*\n * Copyright 2020 Old Corp\n *\n * Licensed under the MIT license\n *\n * Package main runs the server\n */\npackage main\n"
+            ),
+            "/*\n * Copyright 2020-2024 New Corp\n * SPDX-License-Identifier: MIT\n *\n * Package main runs the server\n */\npackage main\n"
+        );
+    }
+
+    #[test]
+    fn test_keeps_comments_mentioning_licenses() {
+        assert_eq!(
+            license_header("src/lib.rs", "//! Parses license files\n\nuse std::fs;\n"),
+            "// Copyright 2024 New Corp\n// SPDX-License-Identifier: MIT\n\n//! Parses license files\n\nuse std::fs;\n"
+        );
+    }
+
+    #[test]
+    fn test_block_comments() {
+        assert_eq!(
+            license_header(
+                "main.go",
+                "/*\n * Copyright 2020 Old Corp\n * Licensed under the MIT license\n */\n\npackage main\n"
+            ),
+            "/*\n * Copyright 2020-2024 New Corp\n * SPDX-License-Identifier: MIT\n */\n\npackage main\n"
+        );
+        assert_eq!(
+            license_header("Utils.java", "/**\n * Helpers for dates\n */\nclass Utils {}\n"),
+            "// Copyright 2024 New Corp\n// SPDX-License-Identifier: MIT\n\n/**\n * Helpers for dates\n */\nclass Utils {}\n"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::convert::TryFrom;
+
+use regex::Regex;
+use serde::Deserialize;
+use tracing::trace;
+
+/// Inserts text next to a line or at the start or end of a file, unless the text is already there
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "InsertConfig")]
+pub struct InsertProcessor {
+    lines: Vec<String>,
+    position: InsertPosition,
+}
+
+#[derive(Debug)]
+enum InsertPosition {
+    Before(Regex),
+    After(Regex),
+    Start,
+    End,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InsertConfig {
+    text: String,
+    /// Inserts before the first line matching it
+    #[serde(default, with = "serde_regex")]
+    before: Option<Regex>,
+    /// Inserts after the first line matching it
+    #[serde(default, with = "serde_regex")]
+    after: Option<Regex>,
+    at: Option<Edge>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Edge {
+    Start,
+    End,
+}
+
+impl TryFrom<InsertConfig> for InsertProcessor {
+    type Error = String;
+
+    fn try_from(config: InsertConfig) -> Result<Self, Self::Error> {
+        let position = match (config.before, config.after, config.at) {
+            (Some(before), None, None) => InsertPosition::Before(before),
+            (None, Some(after), None) => InsertPosition::After(after),
+            (None, None, Some(Edge::Start)) => InsertPosition::Start,
+            (None, None, Some(Edge::End)) => InsertPosition::End,
+            _ => return Err("exactly one of before, after or at has to be set".to_owned()),
+        };
+        let lines = config
+            .text
+            .trim_end_matches('\n')
+            .split('\n')
+            .map(str::to_owned)
+            .collect();
+        Ok(Self { lines, position })
+    }
+}
+
+impl InsertProcessor {
+    pub fn process(&self, text: &mut String) -> bool {
+        let mut lines = split_lines(text);
+        let already_present = lines.windows(self.lines.len()).any(|window| {
+            window
+                .iter()
+                .zip(&self.lines)
+                .all(|(a, b)| a.trim_end() == b)
+        });
+        if already_present {
+            return false;
+        }
+
+        let index = match &self.position {
+            InsertPosition::Start => 0,
+            InsertPosition::End => lines.len(),
+            InsertPosition::Before(regex) | InsertPosition::After(regex) => {
+                let found = match lines.iter().position(|l| regex.is_match(l)) {
+                    Some(found) => found,
+                    None => {
+                        trace!(pattern = regex.as_str(), "no line to insert next to");
+                        return false;
+                    }
+                };
+                match self.position {
+                    InsertPosition::After(_) => found + 1,
+                    _ => found,
+                }
+            }
+        };
+        lines.splice(index..index, self.lines.iter().map(String::as_str));
+        *text = join_lines(&lines, text);
+        true
+    }
+}
+
+/// Removes every line matching `pattern`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoveLinesProcessor {
+    #[serde(with = "serde_regex")]
+    pattern: Regex,
+}
+
+impl RemoveLinesProcessor {
+    pub fn process(&self, text: &mut String) -> bool {
+        let lines = split_lines(text);
+        let kept = lines
+            .iter()
+            .copied()
+            .filter(|l| !self.pattern.is_match(l))
+            .collect::<Vec<_>>();
+        if kept.len() == lines.len() {
+            return false;
+        }
+        *text = join_lines(&kept, text);
+        true
+    }
+}
+
+/// Appends the lines missing from list files like `.gitignore` or `requirements.txt`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnsureLinesProcessor {
+    lines: Vec<String>,
+}
+
+impl EnsureLinesProcessor {
+    pub fn process(&self, text: &mut String) -> bool {
+        let mut lines = split_lines(text);
+        let missing = self
+            .lines
+            .iter()
+            .filter(|wanted| !lines.iter().any(|l| l.trim() == wanted.trim()))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return false;
+        }
+        lines.extend(missing);
+        *text = join_lines(&lines, text);
+        true
+    }
+}
+
+/// Lines without the trailing newline of the file, so appending never glues lines together
+fn split_lines(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return vec![];
+    }
+    text.strip_suffix('\n')
+        .unwrap_or(text)
+        .split('\n')
+        .collect()
+}
+
+fn join_lines(lines: &[&str], original: &str) -> String {
+    let mut output = lines.join("\n");
+    if !lines.is_empty() && (original.ends_with('\n') || original.is_empty()) {
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnsureLinesProcessor, InsertProcessor, RemoveLinesProcessor};
+
+    fn insert(config: &str, text: &str) -> String {
+        let processor: InsertProcessor = toml::from_str(config).unwrap();
+        let mut text = text.to_owned();
+        processor.process(&mut text);
+        assert!(!processor.process(&mut text));
+        text
+    }
+
+    #[test]
+    fn test_insert() {
+        assert_eq!(
+            insert(
+                "text = \"import sys\"\nafter = \"^import os$\"",
+                "import os\n\nprint()\n"
+            ),
+            "import os\nimport sys\n\nprint()\n"
+        );
+        assert_eq!(
+            insert("text = \"[tool]\"\nbefore = \"^\\\\[build\"", "[build]\n"),
+            "[tool]\n[build]\n"
+        );
+        assert_eq!(
+            insert("text = \"# Header\\n\"\nat = \"start\"", "body"),
+            "# Header\nbody"
+        );
+        assert_eq!(insert("text = \"end\"\nat = \"end\"", "body"), "body\nend");
+        assert_eq!(
+            insert("text = \"x\"\nafter = \"missing\"", "body\n"),
+            "body\n"
+        );
+        assert!(toml::from_str::<InsertProcessor>("text = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_remove_lines() {
+        let processor: RemoveLinesProcessor = toml::from_str(r#"pattern = "^\\s*sudo: ""#).unwrap();
+        let mut text = "language: python\nsudo: false\ndist: xenial\n".to_owned();
+
+        assert!(processor.process(&mut text));
+        assert_eq!(text, "language: python\ndist: xenial\n");
+        assert!(!processor.process(&mut text));
+    }
+
+    #[test]
+    fn test_ensure_lines() {
+        let processor: EnsureLinesProcessor =
+            toml::from_str(r#"lines = [".env", "target/"]"#).unwrap();
+        let mut text = "target/\n*.log".to_owned();
+
+        assert!(processor.process(&mut text));
+        assert_eq!(text, "target/\n*.log\n.env");
+        assert!(!processor.process(&mut text));
+
+        let mut text = String::new();
+        assert!(processor.process(&mut text));
+        assert_eq!(text, ".env\ntarget/\n");
+    }
+}
@@ -1,19 +1,47 @@
+pub mod branch;
+pub mod checkout;
+pub mod condition;
+pub mod dependency;
 pub mod executor;
 pub mod glob_pattern;
+pub mod image;
+pub mod license;
+pub mod lines;
+pub mod regex_processor;
+pub mod rename;
+pub mod syntax;
+pub mod template;
+pub mod text_file;
+pub mod variables;
 
-use std::borrow::Cow;
+use std::{collections::HashMap, fmt, time::Duration};
 
-use camino::Utf8Path;
-use color_eyre::{eyre::Context, Result};
-use regex::Regex;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
 use serde::Deserialize;
 use tokio::fs;
+use toml::Value;
 use tracing::instrument;
 
 use crate::providers::{GithubProvider, Provider};
 
+use self::branch::BranchUpdate;
+use self::checkout::CloneOptions;
+use self::condition::Condition;
+use self::dependency::DependencyProcessor;
 pub use self::executor::PlanExecutor;
 use self::glob_pattern::GlobPattern;
+use self::image::{ActionProcessor, ImageProcessor};
+use self::license::LicenseHeaderProcessor;
+use self::lines::{EnsureLinesProcessor, InsertProcessor, RemoveLinesProcessor};
+use self::regex_processor::RegexProcessor;
+use self::rename::RenameProcessor;
+use self::syntax::SyntaxProcessor;
+use self::text_file::FileEncoding;
+use self::variables::VariableOverride;
 
 #[cfg(test)]
 use crate::providers::tests::TestProvider;
@@ -24,17 +52,46 @@ pub struct Plan {
     git_message: String,
     pull_request_title: Option<String>,
     pull_request_body: Option<String>,
-    #[serde(rename = "files")]
+    #[serde(rename = "files", default)]
     file_operations: Vec<FileOperation>,
+    #[serde(rename = "create_files", default)]
+    file_creations: Vec<FileCreation>,
+    #[serde(default)]
+    patches: Vec<Patch>,
     provider: PlanProvider,
+    #[serde(default)]
+    git_protocol: GitProtocol,
+    #[serde(default)]
+    clone: CloneOptions,
+    #[serde(default)]
+    branch_update: BranchUpdate,
+    /// Seconds a repository listing is used before it is refreshed
+    repository_cache_ttl: Option<u64>,
     #[serde(rename = "repositories")]
     /// There is no default just to be explicit and avoid applying changes on all repositories
     repository_allow_filters: Vec<GlobPattern>,
     #[serde(rename = "deny_repositories", default)]
     repository_deny_filters: Vec<GlobPattern>,
+    /// Used as `${var:NAME}` anywhere in the plan
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    #[serde(default)]
+    variable_overrides: Vec<VariableOverride>,
+    /// Plan before replacing `${env:NAME}` and `${var:NAME}`, so it can be parsed again with the
+    /// variables of each repository without keeping secrets around
+    #[serde(skip, default = "empty_source")]
+    source: PlanSource,
+    /// Folder of the plan file, used to resolve relative paths inside of it
+    #[serde(skip)]
+    directory: Utf8PathBuf,
+    /// Plan file, tells plans apart on the cache
+    #[serde(skip)]
+    path: Option<Utf8PathBuf>,
 }
 
-#[derive(Debug, Deserialize)]
+// Only the empty test provider makes the variants differ in size
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "name", rename_all = "snake_case")]
 pub enum PlanProvider {
     Github(GithubProvider),
@@ -42,29 +99,111 @@ pub enum PlanProvider {
     Test(TestProvider),
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum GitProtocol {
+    #[default]
+    Ssh,
+    /// Clones and pushes with the provider token, for machines without ssh keys
+    Https,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FileOperation {
     #[serde(rename = "glob")]
     pattern: GlobPattern,
+    /// Files or folders that are never touched, even when matched by `glob`
+    #[serde(default)]
+    exclude: Vec<GlobPattern>,
+    /// Skip files ignored by .gitignore, .git/info/exclude and .ignore files
+    #[serde(default = "default_true")]
+    gitignore: bool,
+    /// Encoding used when a file has no BOM, utf-8 by default
+    #[serde(default)]
+    encoding: FileEncoding,
+    #[serde(default)]
     processors: Vec<Processor>,
+    /// Files or repositories that do not match are skipped
+    #[serde(default)]
+    when: Condition,
+    /// Delete matched files after running the processors
+    #[serde(default)]
+    delete: bool,
+    /// Move matched files, `$1`, `${2}`... are replaced by what each glob wildcard matched
+    rename_to: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum Processor {
-    Regex(RegexProcessor),
+pub struct FileCreation {
+    /// Path relative to the repository root
+    path: Utf8PathBuf,
+    #[serde(flatten)]
+    source: FileSource,
+    #[serde(default)]
+    if_exists: IfExists,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct RegexProcessor {
-    operations: Vec<RegexOperation>,
+#[serde(rename_all = "snake_case")]
+pub enum FileSource {
+    /// Inline template
+    Contents(String),
+    /// Template file, relative paths are resolved from the plan file folder
+    Template(Utf8PathBuf),
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IfExists {
+    #[default]
+    Skip,
+    Overwrite,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct RegexOperation {
-    #[serde(with = "serde_regex")]
-    from: Regex,
-    to: String,
+pub struct Patch {
+    /// Unified diff or git format-patch file, relative paths are resolved from the plan file folder
+    path: Utf8PathBuf,
+    #[serde(default)]
+    on_failure: OnFailure,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    /// Log and carry on with the rest of the repository
+    #[default]
+    Warn,
+    /// Stop processing the repository
+    Error,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Processor {
+    /// Checked against the file contents right before this processor runs
+    #[serde(default)]
+    when: Condition,
+    #[serde(flatten)]
+    kind: ProcessorKind,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProcessorKind {
+    Regex(RegexProcessor),
+    Syntax(SyntaxProcessor),
+    RenameIdentifier(RenameProcessor),
+    Dependency(DependencyProcessor),
+    Image(ImageProcessor),
+    Action(ActionProcessor),
+    LicenseHeader(LicenseHeaderProcessor),
+    Insert(InsertProcessor),
+    RemoveLines(RemoveLinesProcessor),
+    EnsureLines(EnsureLinesProcessor),
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[instrument]
@@ -73,23 +212,118 @@ pub async fn plan_from_file(path: &Utf8Path) -> Result<Plan> {
         .await
         .wrap_err_with(|| format!("failed to read plan file from {:?}", path))?;
 
-    plan_from_str(&contents).wrap_err_with(|| format!("failed to parse {:?}", path))
+    let mut plan =
+        plan_from_str(&contents).wrap_err_with(|| format!("failed to parse {:?}", path))?;
+    if let Some(directory) = path.parent() {
+        plan.directory = directory.to_owned();
+    }
+    let path = fs::canonicalize(path)
+        .await
+        .ok()
+        .and_then(|path| Utf8PathBuf::from_path_buf(path).ok())
+        .unwrap_or_else(|| path.to_owned());
+    plan.path = Some(path);
+    Ok(plan)
 }
 
 #[instrument(skip(plan))]
 pub fn plan_from_str(plan: &str) -> Result<Plan> {
-    toml::from_str(plan).wrap_err("failed to parse plan")
+    let source: Value = toml::from_str(plan).wrap_err("failed to parse plan")?;
+    let variables = match source.get("variables") {
+        Some(variables) => {
+            let mut variables = variables.clone();
+            variables::interpolate_env(&mut variables)?;
+            variables.try_into().wrap_err("failed to parse variables")?
+        }
+        None => HashMap::new(),
+    };
+    Plan::from_source(source, &variables)
+}
+
+/// Values written on the plan, like tokens, never show up on `Debug` output
+struct PlanSource(Value);
+
+impl fmt::Debug for PlanSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+fn empty_source() -> PlanSource {
+    PlanSource(Value::Table(Default::default()))
 }
 
 impl Plan {
-    pub fn get_provider(&self) -> Box<&dyn Provider> {
+    /// Parses the plan again with the variables of a repository
+    pub fn for_repository(&self, repository_name: &str) -> Result<Plan> {
+        let variables = variables::repository_variables(
+            &self.variables,
+            &self.variable_overrides,
+            repository_name,
+        );
+        let mut plan = Self::from_source(self.source.0.clone(), &variables)
+            .wrap_err_with(|| format!("failed to parse plan for {}", repository_name))?;
+        plan.directory = self.directory.clone();
+        plan.path = self.path.clone();
+        // Keeps the credentials resolved for the whole plan
+        plan.provider = self.provider.clone();
+        Ok(plan)
+    }
+
+    fn from_source(source: Value, variables: &HashMap<String, String>) -> Result<Plan> {
+        let mut value = source.clone();
+        variables::interpolate_all(&mut value, variables)?;
+        let mut plan: Plan = value.try_into().wrap_err("failed to parse plan")?;
+        for operation in &plan.file_operations {
+            operation.validate()?;
+        }
+        plan.source = PlanSource(source);
+        Ok(plan)
+    }
+
+    pub fn get_provider(&self) -> &dyn Provider {
         match &self.provider {
-            PlanProvider::Github(provider) => Box::new(provider),
+            PlanProvider::Github(provider) => provider,
+            #[cfg(test)]
+            PlanProvider::Test(provider) => provider,
+        }
+    }
+
+    pub async fn resolve_credentials(&mut self) -> Result<()> {
+        match &mut self.provider {
+            PlanProvider::Github(provider) => provider
+                .resolve_credentials(&self.directory)
+                .await
+                .wrap_err("failed to resolve github credentials"),
             #[cfg(test)]
-            PlanProvider::Test(provider) => Box::new(provider),
+            PlanProvider::Test(_) => Ok(()),
         }
     }
 
+    pub fn repository_cache_ttl(&self) -> Option<Duration> {
+        self.repository_cache_ttl.map(Duration::from_secs)
+    }
+
+    /// Name of the plan file with a hash of its path, `plan` for plans not read from a file
+    pub fn id(&self) -> String {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return "plan".to_owned(),
+        };
+        // FNV-1a, which unlike the std hasher does not change between Rust versions
+        let hash = path
+            .as_str()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        format!("{}-{:08x}", path.file_stem().unwrap_or("plan"), hash as u32)
+    }
+
+    pub fn branch_name(&self) -> &str {
+        &self.branch_name
+    }
+
     pub fn repository_allowed(&self, repository_name: &str) -> bool {
         self.repository_allow_filters
             .iter()
@@ -104,28 +338,83 @@ impl Plan {
     }
 }
 
+impl FileOperation {
+    fn validate(&self) -> Result<()> {
+        if self.delete && self.rename_to.is_some() {
+            return Err(eyre!(
+                "[[files]] with glob {:?} cannot both delete and rename files",
+                self.pattern.as_str()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FileCreation {
+    pub async fn template(&self, plan_directory: &Utf8Path) -> Result<String> {
+        match &self.source {
+            FileSource::Contents(contents) => Ok(contents.clone()),
+            FileSource::Template(path) => {
+                let path = plan_directory.join(path);
+                fs::read_to_string(&path)
+                    .await
+                    .wrap_err_with(|| format!("failed to read template {}", path))
+            }
+        }
+    }
+}
+
 impl Processor {
-    pub fn process(&self, text: &mut String) -> bool {
-        let mut changed = false;
+    pub fn process(&self, file: &Utf8Path, text: &mut String) -> Result<bool> {
+        if !self.when.matches_contents(text) {
+            return Ok(false);
+        }
+        self.kind.process(file, text)
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.when
+    }
+
+    /// Whether `post_command` can return a command
+    pub fn has_post_command(&self) -> bool {
+        match &self.kind {
+            ProcessorKind::Dependency(processor) => processor.has_lockfile_command(),
+            _ => false,
+        }
+    }
+
+    /// Command to run next to `file` after this processor changed it
+    pub fn post_command(&self, file: &Utf8Path) -> Result<Option<Vec<String>>> {
+        match &self.kind {
+            ProcessorKind::Dependency(processor) => processor.lockfile_command(file),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl ProcessorKind {
+    pub fn process(&self, file: &Utf8Path, text: &mut String) -> Result<bool> {
         match self {
-            Processor::Regex(processor) => {
-                for operation in &processor.operations {
-                    if let Cow::Owned(new_text) = operation.from.replace_all(&text, &operation.to) {
-                        *text = new_text;
-                        changed = true;
-                    }
-                }
-                changed
-            }
+            ProcessorKind::Regex(processor) => processor.process(file, text),
+            ProcessorKind::Syntax(processor) => processor.process(file, text),
+            ProcessorKind::RenameIdentifier(processor) => Ok(processor.process(text)),
+            ProcessorKind::Dependency(processor) => processor.process(file, text),
+            ProcessorKind::Image(processor) => Ok(processor.process(file, text)),
+            ProcessorKind::Action(processor) => Ok(processor.process(text)),
+            ProcessorKind::LicenseHeader(processor) => processor.process(file, text),
+            ProcessorKind::Insert(processor) => Ok(processor.process(text)),
+            ProcessorKind::RemoveLines(processor) => Ok(processor.process(text)),
+            ProcessorKind::EnsureLines(processor) => Ok(processor.process(text)),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use camino::Utf8PathBuf;
+    use camino::{Utf8Path, Utf8PathBuf};
 
-    use super::plan_from_file;
+    use super::{plan_from_file, plan_from_str};
 
     #[tokio::test]
     async fn test_filters() {
@@ -136,4 +425,115 @@ mod tests {
         assert!(plan.repository_allowed("abc-rs-my-repo"));
         assert!(!plan.repository_allowed("my-repo-rs"));
     }
+
+    #[test]
+    fn test_processor_conditions() {
+        let plan = plan_from_str(
+            r#"
+            branch_name = "test"
+            git_message = "chore: Changes"
+            repositories = ["*"]
+
+            [provider]
+            name = "test"
+
+            [[files]]
+            glob = "setup.py"
+            when = { file_missing = "pyproject.toml" }
+            processors = [
+                { type = "regex", when = { not_contains = "import os" }, operations = [
+                    { from = "^", to = "import os\n" }
+                ] }
+            ]
+            "#,
+        )
+        .unwrap();
+        let processor = &plan.file_operations[0].processors[0];
+        let mut text = "print(1)\n".to_owned();
+
+        assert!(processor
+            .process(Utf8Path::new("setup.py"), &mut text)
+            .unwrap());
+        assert_eq!(text, "import os\nprint(1)\n");
+        assert!(!processor
+            .process(Utf8Path::new("setup.py"), &mut text)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_repository_variables() {
+        std::env::set_var("PLAN_TEST_BRANCH", "bump");
+        let plan = plan_from_str(
+            r#"
+            branch_name = "${env:PLAN_TEST_BRANCH}-${var:version}"
+            git_message = "chore: Bump to ${var:version}"
+            repositories = ["*"]
+
+            [provider]
+            name = "test"
+
+            [variables]
+            version = "2.0"
+
+            [[variable_overrides]]
+            repositories = ["payments-*"]
+            variables = { version = "1.9" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(plan.branch_name, "bump-2.0");
+        let repository_plan = plan.for_repository("payments-api").unwrap();
+        assert_eq!(repository_plan.branch_name, "bump-1.9");
+        assert_eq!(repository_plan.git_message, "chore: Bump to 1.9");
+        assert_eq!(plan.for_repository("web").unwrap().branch_name, "bump-2.0");
+    }
+
+    #[test]
+    fn test_delete_and_rename() {
+        let error = plan_from_str(
+            r#"
+            branch_name = "test"
+            git_message = "test"
+            repositories = ["*"]
+            provider = { name = "test" }
+
+            [[files]]
+            glob = "docs/*.rst"
+            delete = true
+            rename_to = "docs/$1.md"
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{:?}", error).contains("cannot both delete and rename files"));
+    }
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        std::env::set_var("PLAN_TEST_TOKEN", "bebacafe");
+        let plan = plan_from_str(
+            r#"
+            branch_name = "test"
+            git_message = "test"
+            repositories = ["*"]
+            provider = { name = "test" }
+            pull_request_body = "${env:PLAN_TEST_TOKEN}"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(plan.pull_request_body.as_deref(), Some("bebacafe"));
+        assert_eq!(format!("{:?}", plan).matches("bebacafe").count(), 1);
+
+        let plan = plan_from_str(
+            r#"
+            branch_name = "test"
+            git_message = "test"
+            repositories = ["*"]
+            provider = { name = "github", organization = "org", user = "bot", token = "ghp_literal" }
+            "#,
+        )
+        .unwrap();
+        assert!(!format!("{:?}", plan).contains("ghp_literal"));
+    }
 }
@@ -0,0 +1,198 @@
+use std::{borrow::Cow, convert::TryFrom, num::NonZeroUsize};
+
+use camino::Utf8Path;
+use color_eyre::{eyre::eyre, Result};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use tracing::{info, trace, warn};
+
+use super::OnFailure;
+
+#[derive(Debug, Deserialize)]
+pub struct RegexProcessor {
+    operations: Vec<RegexOperation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RegexOperationConfig")]
+pub struct RegexOperation {
+    from: Regex,
+    to: String,
+    limit: Option<NonZeroUsize>,
+    expect: Option<Expect>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegexOperationConfig {
+    from: String,
+    to: String,
+    #[serde(default)]
+    case_insensitive: bool,
+    /// `^` and `$` match at the start and end of every line
+    #[serde(default)]
+    multi_line: bool,
+    /// `.` also matches `\n`
+    #[serde(default)]
+    dot_matches_new_line: bool,
+    /// Maximum amount of replacements per file, at least 1
+    limit: Option<NonZeroUsize>,
+    expect: Option<Expect>,
+}
+
+/// Asserts how many times an operation matches each file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    #[serde(default)]
+    min: usize,
+    max: Option<usize>,
+    #[serde(default = "default_expect_failure")]
+    on_failure: OnFailure,
+}
+
+fn default_expect_failure() -> OnFailure {
+    OnFailure::Error
+}
+
+impl TryFrom<RegexOperationConfig> for RegexOperation {
+    type Error = regex::Error;
+
+    fn try_from(config: RegexOperationConfig) -> Result<Self, Self::Error> {
+        let from = RegexBuilder::new(&config.from)
+            .case_insensitive(config.case_insensitive)
+            .multi_line(config.multi_line)
+            .dot_matches_new_line(config.dot_matches_new_line)
+            .build()?;
+        Ok(Self {
+            from,
+            to: config.to,
+            limit: config.limit,
+            expect: config.expect,
+        })
+    }
+}
+
+impl RegexProcessor {
+    pub fn process(&self, file: &Utf8Path, text: &mut String) -> Result<bool> {
+        let mut changed = false;
+        for operation in &self.operations {
+            changed |= operation.process(file, text)?;
+        }
+        Ok(changed)
+    }
+}
+
+impl RegexOperation {
+    fn process(&self, file: &Utf8Path, text: &mut String) -> Result<bool> {
+        let matches = self.from.find_iter(text).count();
+        if matches > 0 {
+            info!(file = file.as_str(), pattern = self.from.as_str(), matches);
+        } else {
+            trace!(file = file.as_str(), pattern = self.from.as_str(), matches);
+        }
+        if let Some(expect) = &self.expect {
+            expect.check(file, self.from.as_str(), matches)?;
+        }
+
+        // replacen takes 0 as no limit
+        let limit = self.limit.map(NonZeroUsize::get).unwrap_or(0);
+        match self.from.replacen(text, limit, self.to.as_str()) {
+            // Replacing a match with the same text is not a change
+            Cow::Owned(new_text) if new_text != *text => {
+                *text = new_text;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+impl Expect {
+    fn check(&self, file: &Utf8Path, pattern: &str, matches: usize) -> Result<()> {
+        let in_range = matches >= self.min && self.max.map(|max| matches <= max).unwrap_or(true);
+        if in_range {
+            return Ok(());
+        }
+        let message = format!(
+            "{:?} matched {} times on {}, expected between {} and {}",
+            pattern,
+            matches,
+            file,
+            self.min,
+            self.max
+                .map(|max| max.to_string())
+                .unwrap_or_else(|| "any".to_owned())
+        );
+        match self.on_failure {
+            OnFailure::Error => Err(eyre!(message)),
+            OnFailure::Warn => {
+                warn!("{}", message);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::RegexProcessor;
+
+    fn regex_processor(operations: &str) -> RegexProcessor {
+        toml::from_str(&format!("operations = [{}]", operations)).unwrap()
+    }
+
+    #[test]
+    fn test_options() {
+        let processor = regex_processor(
+            r#"{ from = "^version = .*$", to = "version = 2", case_insensitive = true, multi_line = true, limit = 1 }"#,
+        );
+        let mut text = "VERSION = 1\nversion = 1\n".to_owned();
+
+        assert!(processor
+            .process(Utf8Path::new("file.txt"), &mut text)
+            .unwrap());
+        assert_eq!(text, "version = 2\nversion = 1\n");
+    }
+
+    #[test]
+    fn test_unchanged_replacement() {
+        let processor = regex_processor(r#"{ from = "(version) = 2", to = "$1 = 2" }"#);
+        let mut text = "version = 2\n".to_owned();
+
+        assert!(!processor
+            .process(Utf8Path::new("Cargo.toml"), &mut text)
+            .unwrap());
+        assert!(toml::from_str::<RegexProcessor>(
+            r#"operations = [{ from = "a", to = "b", limit = 0 }]"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_expect() {
+        let processor = regex_processor(
+            r#"{ from = "enabled = True", to = "enabled = False", expect = { min = 1, max = 1 } }"#,
+        );
+        let mut text = "enabled = True\n".to_owned();
+        assert!(processor
+            .process(Utf8Path::new("file.py"), &mut text)
+            .unwrap());
+
+        let mut text = "enabled = True\nenabled = True\n".to_owned();
+        assert!(processor
+            .process(Utf8Path::new("file.py"), &mut text)
+            .is_err());
+        assert_eq!(text, "enabled = True\nenabled = True\n");
+
+        let processor = regex_processor(
+            r#"{ from = "enabled = True", to = "enabled = False", expect = { min = 1, on_failure = "warn" } }"#,
+        );
+        let mut text = String::new();
+        assert!(!processor
+            .process(Utf8Path::new("file.py"), &mut text)
+            .unwrap());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, convert::TryFrom};
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// Renames an identifier in all of its casings at once, `old_client` to `new_client`
+/// also turns `OldClient` into `NewClient`, `OLD_CLIENT` into `NEW_CLIENT` and so on.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RenameConfig")]
+pub struct RenameProcessor {
+    regex: Regex,
+    replacements: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenameConfig {
+    from: String,
+    to: String,
+}
+
+impl TryFrom<RenameConfig> for RenameProcessor {
+    type Error = String;
+
+    fn try_from(config: RenameConfig) -> Result<Self, Self::Error> {
+        let from = split_words(&config.from);
+        let to = split_words(&config.to);
+        if from.is_empty() || to.is_empty() {
+            return Err("identifiers to rename cannot be empty".to_owned());
+        }
+
+        let mut replacements = HashMap::new();
+        for case in &CASES {
+            replacements
+                .entry(case.join(&from))
+                .or_insert_with(|| case.join(&to));
+        }
+        let mut variants = replacements.keys().collect::<Vec<_>>();
+        // Longest first so the alternation prefers `old_client_id` over `old_client`
+        variants.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        let pattern = variants
+            .iter()
+            .map(|v| regex::escape(v))
+            .collect::<Vec<_>>()
+            .join("|");
+        let regex = Regex::new(&pattern).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            regex,
+            replacements,
+        })
+    }
+}
+
+impl RenameProcessor {
+    pub fn process(&self, text: &mut String) -> bool {
+        let mut output = String::with_capacity(text.len());
+        let mut last_end = 0;
+        for found in self.regex.find_iter(text) {
+            let before = text[..found.start()].chars().next_back();
+            let after = text[found.end()..].chars().next();
+            if !is_boundary(before, found.as_str(), after) {
+                continue;
+            }
+            output.push_str(&text[last_end..found.start()]);
+            output.push_str(&self.replacements[found.as_str()]);
+            last_end = found.end();
+        }
+        if last_end == 0 {
+            return false;
+        }
+        output.push_str(&text[last_end..]);
+        let changed = output != *text;
+        *text = output;
+        changed
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Case {
+    Snake,
+    Screaming,
+    Kebab,
+    Camel,
+    Pascal,
+}
+
+const CASES: [Case; 5] = [
+    Case::Snake,
+    Case::Screaming,
+    Case::Kebab,
+    Case::Camel,
+    Case::Pascal,
+];
+
+impl Case {
+    fn join(self, words: &[String]) -> String {
+        match self {
+            Case::Snake => words.join("_"),
+            Case::Screaming => words.join("_").to_uppercase(),
+            Case::Kebab => words.join("-"),
+            Case::Camel => words
+                .iter()
+                .enumerate()
+                .map(|(i, w)| if i == 0 { w.clone() } else { capitalize(w) })
+                .collect(),
+            Case::Pascal => words.iter().map(|w| capitalize(w)).collect(),
+        }
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Splits any casing into lowercase words, `HTTPClient` is `["http", "client"]`
+fn split_words(identifier: &str) -> Vec<String> {
+    let chars = identifier.chars().collect::<Vec<_>>();
+    let mut words = vec![];
+    let mut current = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        let previous = i.checked_sub(1).map(|p| chars[p]);
+        let next = chars.get(i + 1);
+        let starts_word = c.is_uppercase()
+            && previous
+                .map(|p| p.is_lowercase() || p.is_numeric())
+                .unwrap_or(false)
+            || c.is_uppercase()
+                && previous.map(|p| p.is_uppercase()).unwrap_or(false)
+                && next.map(|n| n.is_lowercase()).unwrap_or(false);
+        if starts_word && !current.is_empty() {
+            words.push(std::mem::take(&mut current));
+        }
+        current.extend(c.to_lowercase());
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+/// Anything that is not a letter or digit separates identifiers, so do case changes
+/// like the `O` in `getOldClient`.
+fn is_boundary(before: Option<char>, found: &str, after: Option<char>) -> bool {
+    let first = found.chars().next().unwrap();
+    let last = found.chars().next_back().unwrap();
+    let left = match before {
+        Some(b) if b.is_alphanumeric() => {
+            first.is_uppercase() && (b.is_lowercase() || b.is_numeric())
+        }
+        _ => true,
+    };
+    let right = match after {
+        Some(a) if a.is_alphanumeric() => a.is_uppercase() && !last.is_uppercase(),
+        _ => true,
+    };
+    left && right
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_words, RenameProcessor};
+
+    fn rename(from: &str, to: &str, text: &str) -> String {
+        let processor: RenameProcessor =
+            toml::from_str(&format!("from = {:?}\nto = {:?}", from, to)).unwrap();
+        let mut text = text.to_owned();
+        processor.process(&mut text);
+        text
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words("old_client"), vec!["old", "client"]);
+        assert_eq!(split_words("OldClient"), vec!["old", "client"]);
+        assert_eq!(split_words("HTTPClient"), vec!["http", "client"]);
+        assert_eq!(split_words("old-client-v2"), vec!["old", "client", "v2"]);
+    }
+
+    #[test]
+    fn test_all_cases() {
+        assert_eq!(
+            rename(
+                "old_client",
+                "new_client",
+                "use old_client::OldClient;\nlet oldClient = OLD_CLIENT;\nimage: old-client\n"
+            ),
+            "use new_client::NewClient;\nlet newClient = NEW_CLIENT;\nimage: new-client\n"
+        );
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        assert_eq!(
+            rename(
+                "old_client",
+                "new_client",
+                "getOldClient(my_old_client, OldClientFactory, old_clientele, boldClient)"
+            ),
+            "getNewClient(my_new_client, NewClientFactory, old_clientele, boldClient)"
+        );
+        assert_eq!(
+            rename(
+                "client",
+                "customer",
+                "client Client CLIENT clients myclient"
+            ),
+            "customer Customer CUSTOMER clients myclient"
+        );
+    }
+}
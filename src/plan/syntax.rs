@@ -0,0 +1,373 @@
+use std::{cmp::Reverse, convert::TryFrom};
+
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use tree_sitter::{Language, Parser, Query, QueryCursor};
+
+/// Rewrites code using tree-sitter queries, so only real syntax nodes are touched
+/// and comments or string literals that happen to contain the same text are left alone.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "SyntaxProcessorConfig")]
+pub struct SyntaxProcessor {
+    /// When missing, the language is inferred from the file extension
+    language: Option<SyntaxLanguage>,
+    operations: Vec<SyntaxOperation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SyntaxProcessorConfig {
+    language: Option<SyntaxLanguage>,
+    operations: Vec<SyntaxOperationConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SyntaxOperationConfig {
+    /// A tree-sitter query, predicates like `#eq?` and `#match?` are supported
+    query: String,
+    /// Name of the capture whose node gets replaced
+    #[serde(default = "default_capture")]
+    capture: String,
+    /// Replacement text, `$name` or `${name}` expands to the text of a capture
+    to: String,
+}
+
+#[derive(Debug)]
+pub struct SyntaxOperation {
+    query: String,
+    /// The query compiled for each language it is valid on
+    compiled: Vec<(SyntaxLanguage, Query, u32)>,
+    to: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyntaxLanguage {
+    Rust,
+    Python,
+    Go,
+    Javascript,
+    Typescript,
+    Tsx,
+}
+
+fn default_capture() -> String {
+    "match".to_owned()
+}
+
+impl SyntaxLanguage {
+    const ALL: [SyntaxLanguage; 6] = [
+        Self::Rust,
+        Self::Python,
+        Self::Go,
+        Self::Javascript,
+        Self::Typescript,
+        Self::Tsx,
+    ];
+
+    pub fn from_path(path: &Utf8Path) -> Option<Self> {
+        let language = match path.extension()? {
+            "rs" => Self::Rust,
+            "py" | "pyi" => Self::Python,
+            "go" => Self::Go,
+            "js" | "jsx" | "mjs" | "cjs" => Self::Javascript,
+            "ts" | "mts" | "cts" => Self::Typescript,
+            "tsx" => Self::Tsx,
+            _ => return None,
+        };
+        Some(language)
+    }
+
+    fn grammar(self) -> Language {
+        match self {
+            Self::Rust => tree_sitter_rust::language(),
+            Self::Python => tree_sitter_python::language(),
+            Self::Go => tree_sitter_go::language(),
+            Self::Javascript => tree_sitter_javascript::language(),
+            Self::Typescript => tree_sitter_typescript::language_typescript(),
+            Self::Tsx => tree_sitter_typescript::language_tsx(),
+        }
+    }
+}
+
+impl TryFrom<SyntaxProcessorConfig> for SyntaxProcessor {
+    type Error = String;
+
+    fn try_from(config: SyntaxProcessorConfig) -> Result<Self, Self::Error> {
+        let language = config.language;
+        let operations = config
+            .operations
+            .into_iter()
+            .map(|operation| SyntaxOperation::compile(operation, language))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            language,
+            operations,
+        })
+    }
+}
+
+impl SyntaxProcessor {
+    pub fn process(&self, file: &Utf8Path, text: &mut String) -> Result<bool> {
+        let language = self
+            .language
+            .or_else(|| SyntaxLanguage::from_path(file))
+            .ok_or_else(|| eyre!("failed to infer the language of {}", file))?;
+
+        let mut changed = false;
+        for operation in &self.operations {
+            changed |= operation
+                .process(language, text)
+                .wrap_err_with(|| format!("failed to run query {:?}", operation.query))?;
+        }
+        Ok(changed)
+    }
+}
+
+impl SyntaxOperation {
+    /// Compiles the query once for `language`, or for every language it is valid on when the
+    /// language is inferred from each file
+    fn compile(
+        config: SyntaxOperationConfig,
+        language: Option<SyntaxLanguage>,
+    ) -> Result<Self, String> {
+        let languages = match language {
+            Some(language) => vec![language],
+            None => SyntaxLanguage::ALL.to_vec(),
+        };
+        let mut compiled = vec![];
+        let mut errors = vec![];
+        for language in languages {
+            match Query::new(language.grammar(), &config.query) {
+                Ok(query) => {
+                    let capture_index =
+                        query
+                            .capture_index_for_name(&config.capture)
+                            .ok_or_else(|| {
+                                format!(
+                                    "query {:?} has no capture named @{}",
+                                    config.query, config.capture
+                                )
+                            })?;
+                    compiled.push((language, query, capture_index));
+                }
+                Err(error) => errors.push(format!("{:?}: {}", language, error)),
+            }
+        }
+        if compiled.is_empty() {
+            return Err(format!(
+                "invalid query {:?}, {}",
+                config.query,
+                errors.join(", ")
+            ));
+        }
+        Ok(Self {
+            query: config.query,
+            compiled,
+            to: config.to,
+        })
+    }
+
+    fn process(&self, language: SyntaxLanguage, text: &mut String) -> Result<bool> {
+        let (query, capture_index) = self
+            .compiled
+            .iter()
+            .find(|(l, _, _)| *l == language)
+            .map(|(_, query, capture_index)| (query, *capture_index))
+            .ok_or_else(|| eyre!("query is not valid for {:?}", language))?;
+        let mut parser = Parser::new();
+        parser.set_language(language.grammar())?;
+        let tree = parser
+            .parse(text.as_str(), None)
+            .ok_or_else(|| eyre!("failed to parse file"))?;
+
+        let mut edits = vec![];
+        let mut cursor = QueryCursor::new();
+        for query_match in cursor.matches(query, tree.root_node(), text.as_bytes()) {
+            let target = match query_match
+                .captures
+                .iter()
+                .find(|c| c.index == capture_index)
+            {
+                Some(target) => target.node.byte_range(),
+                None => continue,
+            };
+            let replacement = expand(&self.to, |name| {
+                let index = query.capture_index_for_name(name)?;
+                query_match
+                    .captures
+                    .iter()
+                    .find(|c| c.index == index)
+                    .map(|c| &text[c.node.byte_range()])
+            });
+            edits.push((target, replacement));
+        }
+
+        // Outer captures come before the captures they contain
+        edits.sort_by_key(|(range, _)| (range.start, Reverse(range.end)));
+        let mut output = String::with_capacity(text.len());
+        let mut last_end = 0;
+        for (range, replacement) in edits {
+            if range.start < last_end {
+                // Overlapping matches, the outermost one wins
+                continue;
+            }
+            output.push_str(&text[last_end..range.start]);
+            output.push_str(&replacement);
+            last_end = range.end;
+        }
+        output.push_str(&text[last_end..]);
+
+        if output == *text {
+            return Ok(false);
+        }
+        *text = output;
+        Ok(true)
+    }
+}
+
+/// Expands `$name` and `${name}` with the text of captures, `$$` is a literal `$`
+fn expand<'a>(template: &str, capture_text: impl Fn(&str) -> Option<&'a str>) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\$(?:(\$)|\{([\w.-]+)\}|([\w.-]+))").unwrap();
+    }
+    RE.replace_all(template, |c: &Captures| {
+        if c.get(1).is_some() {
+            return "$".to_owned();
+        }
+        let name = c.get(2).or_else(|| c.get(3)).unwrap().as_str();
+        capture_text(name).unwrap_or_default().to_owned()
+    })
+    .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::{SyntaxLanguage, SyntaxProcessor};
+
+    #[test]
+    fn test_only_rewrites_calls() {
+        let processor: SyntaxProcessor = toml::from_str(
+            r#"
+            operations = [
+                { query = '(call function: (identifier) @match (#eq? @match "wrong_function_name"))', to = "right_function_name" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let mut text = concat!(
+            "# wrong_function_name is deprecated\n",
+            "print(\"wrong_function_name\")\n",
+            "wrong_function_name(1)\n",
+        )
+        .to_owned();
+
+        assert!(processor
+            .process(Utf8Path::new("main.py"), &mut text)
+            .unwrap());
+        assert_eq!(
+            text,
+            concat!(
+                "# wrong_function_name is deprecated\n",
+                "print(\"wrong_function_name\")\n",
+                "right_function_name(1)\n",
+            )
+        );
+        assert!(!processor
+            .process(Utf8Path::new("main.py"), &mut text)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_captures_in_replacement() {
+        let processor: SyntaxProcessor = toml::from_str(
+            r#"
+            language = "rust"
+            operations = [
+                { query = '(call_expression function: (field_expression value: (_) @receiver field: (field_identifier) @method (#eq? @method "unwrap"))) @match', to = "${receiver}.expect(\"$method\")" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let mut text = "fn main() { let a = b.unwrap(); }".to_owned();
+
+        assert!(processor
+            .process(Utf8Path::new("no-extension"), &mut text)
+            .unwrap());
+        assert_eq!(text, "fn main() { let a = b.expect(\"unwrap\"); }");
+    }
+
+    #[test]
+    fn test_nested_captures() {
+        let processor: SyntaxProcessor = toml::from_str(
+            r#"
+            language = "python"
+            operations = [
+                { query = '(call function: (_) @function) @match', to = "run($function)" },
+            ]
+            "#,
+        )
+        .unwrap();
+        // `f(1)(2)` and the `f(1)` inside it start at the same byte
+        let mut text = "f(1)(2)\ng(h(3))\n".to_owned();
+
+        assert!(processor
+            .process(Utf8Path::new("main.py"), &mut text)
+            .unwrap());
+        assert_eq!(text, "run(f(1))\nrun(g)\n");
+    }
+
+    #[test]
+    fn test_queries_are_checked_on_load() {
+        let error = toml::from_str::<SyntaxProcessor>(
+            "language = \"rust\"\noperations = [{ query = '(call) @match', to = \"\" }]",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("invalid query"), "{}", error);
+        let error = toml::from_str::<SyntaxProcessor>(
+            "operations = [{ query = '(identifier) @name', to = \"\" }]",
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("no capture named @match"),
+            "{}",
+            error
+        );
+
+        // Inferred languages only use the query on the languages it is valid on
+        let processor: SyntaxProcessor =
+            toml::from_str("operations = [{ query = '(call) @match', to = \"x\" }]").unwrap();
+        let mut text = "f()\n".to_owned();
+        assert!(processor
+            .process(Utf8Path::new("main.py"), &mut text)
+            .unwrap());
+        assert_eq!(text, "x\n");
+        let error = processor
+            .process(Utf8Path::new("main.rs"), &mut "f();".to_owned())
+            .unwrap_err();
+        assert!(format!("{:?}", error).contains("not valid for Rust"));
+    }
+
+    #[test]
+    fn test_language_from_path() {
+        assert_eq!(
+            SyntaxLanguage::from_path(Utf8Path::new("src/main.rs")),
+            Some(SyntaxLanguage::Rust)
+        );
+        assert_eq!(
+            SyntaxLanguage::from_path(Utf8Path::new("web/app.tsx")),
+            Some(SyntaxLanguage::Tsx)
+        );
+        assert_eq!(SyntaxLanguage::from_path(Utf8Path::new("main.tf")), None);
+        assert_eq!(SyntaxLanguage::from_path(Utf8Path::new("README")), None);
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use color_eyre::{eyre::eyre, Result};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+/// Replaces `{{ name }}` placeholders with their values, unknown names are an error
+/// to avoid creating files with half rendered contents.
+pub fn render(template: &str, variables: &HashMap<String, String>) -> Result<String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\{\{\s*([\w.-]+)\s*\}\}").unwrap();
+    }
+    let mut missing = vec![];
+    let rendered = RE.replace_all(template, |c: &Captures| {
+        let name = &c[1];
+        match variables.get(name) {
+            Some(value) => value.clone(),
+            None => {
+                missing.push(name.to_owned());
+                String::new()
+            }
+        }
+    });
+    if !missing.is_empty() {
+        return Err(eyre!("unknown template variables {:?}", missing));
+    }
+    Ok(rendered.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::render;
+
+    #[test]
+    fn test_render() {
+        let mut variables = HashMap::new();
+        variables.insert("repository.name".to_owned(), "my-repo".to_owned());
+
+        assert_eq!(
+            render("* @org/{{ repository.name }}-owners", &variables).unwrap(),
+            "* @org/my-repo-owners"
+        );
+        assert_eq!(
+            render("{{repository.name}}", &variables).unwrap(),
+            "my-repo"
+        );
+        assert!(render("{{ repository.missing }}", &variables).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use color_eyre::{eyre::eyre, Result};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use serde::{de::Visitor, Deserialize, Deserializer};
+
+/// How many bytes are checked for NUL bytes when looking for binary files, same as git
+const BINARY_CHECK_LENGTH: usize = 8000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FileEncoding(&'static Encoding);
+
+impl Default for FileEncoding {
+    fn default() -> Self {
+        Self(UTF_8)
+    }
+}
+
+/// What has to be restored when writing a file back so only the processed text changes
+#[derive(Debug, PartialEq)]
+pub struct TextFile {
+    encoding: &'static Encoding,
+    bom: bool,
+    crlf: bool,
+    trailing_newline: bool,
+}
+
+impl TextFile {
+    /// Decodes `bytes` into text with `\n` line endings, `None` means the file is binary or
+    /// not valid in the expected encoding and should be left alone.
+    pub fn decode(bytes: &[u8], encoding: FileEncoding) -> Option<(String, TextFile)> {
+        let (encoding, bom_length) = match Encoding::for_bom(bytes) {
+            Some((encoding, bom_length)) => (encoding, bom_length),
+            None => (encoding.0, 0),
+        };
+        let bytes = &bytes[bom_length..];
+        let is_utf16 = encoding == UTF_16LE || encoding == UTF_16BE;
+        if !is_utf16 && bytes.iter().take(BINARY_CHECK_LENGTH).any(|b| *b == 0) {
+            return None;
+        }
+
+        let text = encoding.decode_without_bom_handling_and_without_replacement(bytes)?;
+        let crlf = text.contains("\r\n") && !text.replace("\r\n", "").contains('\n');
+        let text = if crlf {
+            text.replace("\r\n", "\n")
+        } else {
+            text.into_owned()
+        };
+        let file = TextFile {
+            encoding,
+            bom: bom_length > 0,
+            crlf,
+            trailing_newline: text.ends_with('\n'),
+        };
+        Some((text, file))
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>> {
+        let mut text = text.to_owned();
+        if !text.is_empty() {
+            if self.trailing_newline && !text.ends_with('\n') {
+                text.push('\n');
+            } else if !self.trailing_newline && text.ends_with('\n') {
+                text.pop();
+            }
+        }
+        if self.crlf {
+            text = text.replace("\r\n", "\n").replace('\n', "\r\n");
+        }
+
+        let mut output = vec![];
+        // encoding_rs only encodes utf-16 as utf-8, as the web never needs it
+        if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            let little_endian = self.encoding == UTF_16LE;
+            if self.bom {
+                output.extend(encode_utf16_unit(0xFEFF, little_endian));
+            }
+            for unit in text.encode_utf16() {
+                output.extend(encode_utf16_unit(unit, little_endian));
+            }
+            return Ok(output);
+        }
+
+        if self.bom {
+            output.extend(b"\xEF\xBB\xBF");
+        }
+        let (bytes, _, had_errors) = self.encoding.encode(&text);
+        if had_errors {
+            return Err(eyre!(
+                "processed text cannot be represented as {}",
+                self.encoding.name()
+            ));
+        }
+        output.extend(bytes.iter());
+        Ok(output)
+    }
+}
+
+fn encode_utf16_unit(unit: u16, little_endian: bool) -> [u8; 2] {
+    if little_endian {
+        unit.to_le_bytes()
+    } else {
+        unit.to_be_bytes()
+    }
+}
+
+struct FileEncodingVisitor;
+impl<'de> Visitor<'de> for FileEncodingVisitor {
+    type Value = FileEncoding;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an encoding label like utf-8, latin1 or utf-16le")
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        match Encoding::for_label(v.as_bytes()) {
+            Some(encoding) => Ok(FileEncoding(encoding)),
+            None => Err(E::custom(format!("unknown encoding {:?}", v))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FileEncoding {
+    fn deserialize<D>(deserializer: D) -> Result<FileEncoding, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(FileEncodingVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::WINDOWS_1252;
+
+    use super::{FileEncoding, TextFile};
+
+    fn round_trip(bytes: &[u8], encoding: FileEncoding) -> Vec<u8> {
+        let (text, file) = TextFile::decode(bytes, encoding).unwrap();
+        file.encode(&text.replace("old", "new")).unwrap()
+    }
+
+    #[test]
+    fn test_preserves_line_endings_and_bom() {
+        assert_eq!(
+            round_trip(b"\xEF\xBB\xBFold\r\nline\r\n", FileEncoding::default()),
+            b"\xEF\xBB\xBFnew\r\nline\r\n"
+        );
+        assert_eq!(
+            round_trip(b"old\nline", FileEncoding::default()),
+            b"new\nline"
+        );
+        // Mixed line endings are left as they are
+        assert_eq!(
+            round_trip(b"old\r\nline\n", FileEncoding::default()),
+            b"new\r\nline\n"
+        );
+    }
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!(
+            round_trip(b"old caf\xE9\n", FileEncoding(WINDOWS_1252)),
+            b"new caf\xE9\n"
+        );
+        assert_eq!(
+            round_trip(b"\xFF\xFEo\0l\0d\0\n\0", FileEncoding::default()),
+            b"\xFF\xFEn\0e\0w\0\n\0"
+        );
+        let encoding: FileEncoding = serde_json::from_str(r#""latin1""#).unwrap();
+        assert_eq!(encoding.0, WINDOWS_1252);
+    }
+
+    #[test]
+    fn test_skips_binary_and_invalid_files() {
+        assert_eq!(
+            TextFile::decode(b"\x89PNG\r\n\x1a\n\0\0", FileEncoding::default()),
+            None
+        );
+        assert_eq!(TextFile::decode(b"caf\xE9", FileEncoding::default()), None);
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use color_eyre::{eyre::eyre, Result};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use toml::Value;
+
+use super::glob_pattern::GlobPattern;
+
+/// Values used instead of the `[variables]` of the plan on the matching repositories,
+/// later overrides win when more than one matches.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariableOverride {
+    repositories: Vec<GlobPattern>,
+    variables: HashMap<String, String>,
+}
+
+/// Variables for a repository, the plan defaults with its overrides on top
+pub fn repository_variables(
+    defaults: &HashMap<String, String>,
+    overrides: &[VariableOverride],
+    repository_name: &str,
+) -> HashMap<String, String> {
+    let mut variables = defaults.clone();
+    for variable_override in overrides
+        .iter()
+        .filter(|o| o.repositories.iter().any(|r| r.matches(repository_name)))
+    {
+        variables.extend(variable_override.variables.clone());
+    }
+    variables
+}
+
+/// Replaces `${env:NAME}` on every string of the plan, missing variables are an error
+pub fn interpolate_env(value: &mut Value) -> Result<()> {
+    interpolate(value, &[("env", &env_lookup)])
+}
+
+/// Replaces `${env:NAME}` and `${var:NAME}` on every string of the plan, missing variables are
+/// an error
+pub fn interpolate_all(value: &mut Value, variables: &HashMap<String, String>) -> Result<()> {
+    interpolate(
+        value,
+        &[
+            ("env", &env_lookup),
+            ("var", &|name| variables.get(name).cloned()),
+        ],
+    )
+}
+
+fn env_lookup(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+type Lookup<'a> = (&'a str, &'a dyn Fn(&str) -> Option<String>);
+
+/// `$${kind:NAME}` is kept as a literal `${kind:NAME}`
+fn interpolate(value: &mut Value, lookups: &[Lookup]) -> Result<()> {
+    let mut missing = vec![];
+    interpolate_value(value, lookups, &mut missing);
+    missing.sort();
+    missing.dedup();
+    let (missing_variables, missing_env): (Vec<_>, Vec<_>) =
+        missing.into_iter().partition(|(kind, _)| kind == "var");
+    if !missing_env.is_empty() {
+        let names = missing_env.into_iter().map(|(_, n)| n).collect::<Vec<_>>();
+        return Err(eyre!("unknown environment variables {:?}", names));
+    }
+    if !missing_variables.is_empty() {
+        let names = missing_variables
+            .into_iter()
+            .map(|(_, n)| n)
+            .collect::<Vec<_>>();
+        return Err(eyre!(
+            "unknown variables {:?}, every variable needs a value on [variables] even when \
+             [[variable_overrides]] set it",
+            names
+        ));
+    }
+    Ok(())
+}
+
+fn interpolate_value(value: &mut Value, lookups: &[Lookup], missing: &mut Vec<(String, String)>) {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\$(\$?)\{(env|var):([\w.-]+)\}").unwrap();
+    }
+    match value {
+        Value::String(text) => {
+            let new_text = RE.replace_all(text, |c: &Captures| {
+                let lookup = match lookups.iter().find(|(kind, _)| *kind == &c[2]) {
+                    Some((_, lookup)) => lookup,
+                    None => return c[0].to_owned(),
+                };
+                if !c[1].is_empty() {
+                    return c[0][1..].to_owned();
+                }
+                lookup(&c[3]).unwrap_or_else(|| {
+                    missing.push((c[2].to_owned(), c[3].to_owned()));
+                    String::new()
+                })
+            });
+            *text = new_text.into_owned();
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|v| interpolate_value(v, lookups, missing)),
+        Value::Table(table) => table
+            .iter_mut()
+            .map(|(_, v)| v)
+            .for_each(|v| interpolate_value(v, lookups, missing)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use toml::Value;
+
+    use super::{interpolate_all, interpolate_env, repository_variables, VariableOverride};
+
+    #[test]
+    fn test_interpolation() {
+        std::env::set_var("VARIABLES_TEST_TOKEN", "secret");
+        let mut value: Value = toml::from_str(
+            r#"
+            token = "${env:VARIABLES_TEST_TOKEN}"
+            operations = [{ from = "${1}", to = "v${var:version}" }]
+            contents = "$${env:VARIABLES_TEST_TOKEN} $${var:version}"
+            "#,
+        )
+        .unwrap();
+        let mut variables = HashMap::new();
+        variables.insert("version".to_owned(), "2".to_owned());
+
+        interpolate_all(&mut value, &variables).unwrap();
+        assert_eq!(value["token"].as_str(), Some("secret"));
+        assert_eq!(value["operations"][0]["from"].as_str(), Some("${1}"));
+        assert_eq!(value["operations"][0]["to"].as_str(), Some("v2"));
+        assert_eq!(
+            value["contents"].as_str(),
+            Some("${env:VARIABLES_TEST_TOKEN} ${var:version}")
+        );
+
+        let mut value: Value =
+            toml::from_str(r#"token = "${env:VARIABLES_TEST_MISSING}""#).unwrap();
+        assert!(interpolate_env(&mut value).is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides: Vec<VariableOverride> = serde_json::from_str(
+            r#"[
+                { "repositories": ["payments-*"], "variables": { "version": "1.9" } },
+                { "repositories": ["payments-legacy"], "variables": { "version": "1.0" } }
+            ]"#,
+        )
+        .unwrap();
+        let mut defaults = HashMap::new();
+        defaults.insert("version".to_owned(), "2.0".to_owned());
+
+        let variables = |name| repository_variables(&defaults, &overrides, name)["version"].clone();
+        assert_eq!(variables("web"), "2.0");
+        assert_eq!(variables("payments-api"), "1.9");
+        assert_eq!(variables("payments-legacy"), "1.0");
+    }
+}
@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::debug;
+
+use crate::{constants::CACHE_DIR, Repository};
+
+/// Listings older than this are refreshed when neither the plan nor the command line set a ttl
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const LISTING_PREFIX: &str = "repositories-";
+
+/// Repositories of an organization as they were listed by the provider
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedListing {
+    pub fetched_at: DateTime<Utc>,
+    /// Listings of another api are never reused
+    pub api_url: String,
+    /// Folder of the clones of these repositories, listings without it never prune clones
+    #[serde(default)]
+    pub clone_namespace: String,
+    pub pages: Vec<CachedPage>,
+}
+
+/// One page of the listing, kept with its ETag so it can be refreshed with a conditional request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedPage {
+    pub url: String,
+    pub etag: Option<String>,
+    pub next: Option<String>,
+    pub repositories: Vec<Repository>,
+}
+
+impl CachedListing {
+    pub fn new(api_url: &str, clone_namespace: &str, pages: Vec<CachedPage>) -> Self {
+        Self {
+            fetched_at: Utc::now(),
+            api_url: api_url.to_owned(),
+            clone_namespace: clone_namespace.to_owned(),
+            pages,
+        }
+    }
+
+    pub fn is_fresh(&self, max_age: Duration, now: DateTime<Utc>) -> bool {
+        match (now - self.fetched_at).to_std() {
+            Ok(age) => age < max_age,
+            // Fetched in the future, the clock changed since
+            Err(_) => false,
+        }
+    }
+
+    pub fn repository_count(&self) -> usize {
+        self.pages.iter().map(|p| p.repositories.len()).sum()
+    }
+
+    pub fn repository_names(&self) -> impl Iterator<Item = &str> {
+        self.pages
+            .iter()
+            .flat_map(|p| p.repositories.iter().map(|r| r.name.as_str()))
+    }
+
+    pub fn into_repositories(self) -> Vec<Repository> {
+        self.pages
+            .into_iter()
+            .flat_map(|p| p.repositories)
+            .collect()
+    }
+}
+
+fn listing_path(directory: &Utf8Path, provider_name: &str, organization: &str) -> Utf8PathBuf {
+    directory.join(format!(
+        "{}{}-{}.json",
+        LISTING_PREFIX, provider_name, organization
+    ))
+}
+
+/// Returns the cached listing when it exists and was fetched from `api_url`
+pub async fn load(
+    provider_name: &str,
+    organization: &str,
+    api_url: &str,
+) -> Result<Option<CachedListing>> {
+    load_from(&CACHE_DIR, provider_name, organization, api_url).await
+}
+
+async fn load_from(
+    directory: &Utf8Path,
+    provider_name: &str,
+    organization: &str,
+    api_url: &str,
+) -> Result<Option<CachedListing>> {
+    let path = listing_path(directory, provider_name, organization);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let listing: CachedListing = match serde_json::from_slice(&fs::read(&path).await?) {
+        Ok(listing) => listing,
+        Err(err) => {
+            debug!(%err, "ignoring cache in an old format");
+            return Ok(None);
+        }
+    };
+    if listing.api_url != api_url {
+        debug!(
+            cached = listing.api_url.as_str(),
+            "ignoring cache of another api"
+        );
+        return Ok(None);
+    }
+    Ok(Some(listing))
+}
+
+pub async fn save(provider_name: &str, organization: &str, listing: &CachedListing) -> Result<()> {
+    save_to(&CACHE_DIR, provider_name, organization, listing).await
+}
+
+async fn save_to(
+    directory: &Utf8Path,
+    provider_name: &str,
+    organization: &str,
+    listing: &CachedListing,
+) -> Result<()> {
+    let contents = serde_json::to_vec_pretty(listing)?;
+    let path = listing_path(directory, provider_name, organization);
+    fs::create_dir_all(directory).await?;
+    fs::write(&path, &contents)
+        .await
+        .wrap_err_with(|| format!("failed to save cache {}", path))?;
+    Ok(())
+}
+
+/// Every cached listing file, with its contents when it could be parsed
+pub async fn listings(directory: &Utf8Path) -> Result<Vec<(Utf8PathBuf, Option<CachedListing>)>> {
+    let mut output = vec![];
+    if !directory.exists() {
+        return Ok(output);
+    }
+    let mut entries = fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = match Utf8PathBuf::from_path_buf(entry.path()) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        let is_listing = path
+            .file_name()
+            .map(|name| name.starts_with(LISTING_PREFIX) && name.ends_with(".json"))
+            .unwrap_or(false);
+        if !is_listing {
+            continue;
+        }
+        let listing = serde_json::from_slice(&fs::read(&path).await?).ok();
+        output.push((path, listing));
+    }
+    output.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use camino::Utf8Path;
+    use chrono::Utc;
+    use tempdir::TempDir;
+
+    use super::{listings, load_from, save_to, CachedListing, CachedPage};
+    use crate::Repository;
+
+    fn listing() -> CachedListing {
+        let repository: Repository = serde_json::from_value(serde_json::json!({
+            "name": "fix-it",
+            "private": true,
+            "fork": false,
+            "ssh_url": "git@github.com:org/fix-it.git",
+            "default_branch": "main",
+        }))
+        .unwrap();
+        CachedListing::new(
+            "https://api.github.com",
+            "github/github.com/org",
+            vec![CachedPage {
+                url: "https://api.github.com/orgs/org/repos".to_string(),
+                etag: Some("\"abc\"".to_string()),
+                next: None,
+                repositories: vec![repository],
+            }],
+        )
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let listing = listing();
+        let later = listing.fetched_at + chrono::Duration::hours(2);
+
+        assert!(listing.is_fresh(Duration::from_secs(3 * 60 * 60), later));
+        assert!(!listing.is_fresh(Duration::from_secs(60 * 60), later));
+        assert!(!listing.is_fresh(Duration::from_secs(0), Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn test_load_and_save() {
+        let directory = TempDir::new("cache").unwrap();
+        let directory = Utf8Path::from_path(directory.path()).unwrap();
+        save_to(directory, "github", "org", &listing())
+            .await
+            .unwrap();
+        std::fs::write(directory.join("repositories-github-old.json"), "[]").unwrap();
+
+        let loaded = load_from(directory, "github", "org", "https://api.github.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.repository_count(), 1);
+        assert_eq!(loaded.pages[0].etag.as_deref(), Some("\"abc\""));
+
+        let other_api = load_from(directory, "github", "org", "https://github.example.com")
+            .await
+            .unwrap();
+        assert!(other_api.is_none());
+        let old_format = load_from(directory, "github", "old", "https://api.github.com")
+            .await
+            .unwrap();
+        assert!(old_format.is_none());
+
+        let found = listings(directory).await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found[0].1.is_none());
+        assert!(found[1].1.is_some());
+    }
+}
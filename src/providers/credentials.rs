@@ -0,0 +1,251 @@
+use std::{fmt, process::Stdio};
+
+use camino::Utf8PathBuf;
+use color_eyre::Result;
+use serde::Deserialize;
+use tokio::{fs, io::AsyncWriteExt, process::Command};
+use tracing::{debug, instrument, trace};
+
+/// A value that never shows up on `Debug` output, so it cannot leak into logs
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"[redacted]\"")
+    }
+}
+
+/// Places to look for credentials missing from the plan, in the order they are listed
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialSource {
+    /// `<PREFIX>_USER` and `<PREFIX>_TOKEN`, plus `GH_TOKEN` for github
+    Env,
+    /// The hosts.yml file of the github cli
+    Gh,
+    /// `git credential fill`, so any configured git credential helper works
+    Git,
+    /// Secret Service keyring through `secret-tool`, stored with the attributes
+    /// `service there-i-fixed-it host <host>`
+    Keyring,
+}
+
+pub fn default_sources() -> Vec<CredentialSource> {
+    vec![
+        CredentialSource::Env,
+        CredentialSource::Gh,
+        CredentialSource::Git,
+        CredentialSource::Keyring,
+    ]
+}
+
+#[derive(Debug, Default)]
+pub struct Credentials {
+    pub user: Option<String>,
+    pub token: Option<Secret>,
+}
+
+impl Credentials {
+    fn is_complete(&self) -> bool {
+        self.user.is_some() && self.token.is_some()
+    }
+
+    /// Fills what is missing, values found first win
+    fn merge(&mut self, other: Credentials) {
+        if self.user.is_none() {
+            self.user = other.user;
+        }
+        if self.token.is_none() {
+            self.token = other.token;
+        }
+    }
+}
+
+/// Looks for what is missing on `credentials` in each source until both user and token are known
+#[instrument(skip(credentials))]
+pub async fn resolve(
+    credentials: &mut Credentials,
+    host: &str,
+    env_prefix: &str,
+    sources: &[CredentialSource],
+) -> Result<()> {
+    for source in sources {
+        if credentials.is_complete() {
+            break;
+        }
+        let found = match source {
+            CredentialSource::Env => from_env(env_prefix),
+            CredentialSource::Gh => from_gh_hosts(host).await?,
+            CredentialSource::Git => from_git(host).await?,
+            CredentialSource::Keyring => from_keyring(host).await?,
+        };
+        if found.user.is_some() || found.token.is_some() {
+            debug!(?source, "found credentials");
+        }
+        credentials.merge(found);
+    }
+    Ok(())
+}
+
+fn from_env(prefix: &str) -> Credentials {
+    let var = |name: String| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let mut token = var(format!("{}_TOKEN", prefix));
+    if token.is_none() && prefix == "GITHUB" {
+        token = var("GH_TOKEN".to_owned());
+    }
+    Credentials {
+        user: var(format!("{}_USER", prefix)),
+        token: token.map(Secret),
+    }
+}
+
+async fn from_gh_hosts(host: &str) -> Result<Credentials> {
+    let path = match gh_hosts_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(Credentials::default()),
+    };
+    let contents = fs::read_to_string(&path).await?;
+    Ok(parse_gh_hosts(&contents, host))
+}
+
+fn gh_hosts_path() -> Option<Utf8PathBuf> {
+    if let Ok(directory) = std::env::var("GH_CONFIG_DIR") {
+        return Some(Utf8PathBuf::from(directory).join("hosts.yml"));
+    }
+    let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(directory) => Utf8PathBuf::from(directory),
+        Err(_) => Utf8PathBuf::from(std::env::var("HOME").ok()?).join(".config"),
+    };
+    Some(config_dir.join("gh").join("hosts.yml"))
+}
+
+/// hosts.yml is a flat map of hosts to `user` and `oauth_token`, a line parser is enough for it
+fn parse_gh_hosts(contents: &str, host: &str) -> Credentials {
+    let mut credentials = Credentials::default();
+    let mut in_host = false;
+    for line in contents.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            in_host = line.trim_end().trim_end_matches(':').trim_matches('"') == host;
+            continue;
+        }
+        if !in_host {
+            continue;
+        }
+        let (key, value) = match line.trim().split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim().trim_matches('"')),
+            None => continue,
+        };
+        match key {
+            "user" if !value.is_empty() && credentials.user.is_none() => {
+                credentials.user = Some(value.to_owned())
+            }
+            "oauth_token" if !value.is_empty() && credentials.token.is_none() => {
+                credentials.token = Some(Secret::new(value))
+            }
+            _ => {}
+        }
+    }
+    credentials
+}
+
+async fn from_git(host: &str) -> Result<Credentials> {
+    let child = Command::new("git")
+        .args(["credential", "fill"])
+        // Never ask on the terminal, a missing credential just means trying the next source
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            trace!(%err, "git is not available");
+            return Ok(Credentials::default());
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(format!("protocol=https\nhost={}\n\n", host).as_bytes())
+            .await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        trace!("git credential fill found nothing");
+        return Ok(Credentials::default());
+    }
+    Ok(parse_git_credentials(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+fn parse_git_credentials(output: &str) -> Credentials {
+    let mut credentials = Credentials::default();
+    for (key, value) in output.lines().filter_map(|l| l.split_once('=')) {
+        match key {
+            "username" => credentials.user = Some(value.to_owned()),
+            "password" => credentials.token = Some(Secret::new(value)),
+            _ => {}
+        }
+    }
+    credentials
+}
+
+async fn from_keyring(host: &str) -> Result<Credentials> {
+    let output = Command::new("secret-tool")
+        .args(["lookup", "service", "there-i-fixed-it", "host", host])
+        .stderr(Stdio::null())
+        .output()
+        .await;
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(_) => return Ok(Credentials::default()),
+        Err(err) => {
+            trace!(%err, "secret-tool is not available");
+            return Ok(Credentials::default());
+        }
+    };
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    Ok(Credentials {
+        user: None,
+        token: Some(token).filter(|t| !t.is_empty()).map(Secret),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_gh_hosts, parse_git_credentials, Secret};
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret::new("bebacafe");
+        assert_eq!(format!("{:?}", secret), "\"[redacted]\"");
+        assert_eq!(secret.expose(), "bebacafe");
+    }
+
+    #[test]
+    fn test_parsers() {
+        let hosts = "github.example.com:\n    oauth_token: other\ngithub.com:\n    user: octocat\n    oauth_token: gho_token\n    git_protocol: ssh\n";
+        let credentials = parse_gh_hosts(hosts, "github.com");
+        assert_eq!(credentials.user.as_deref(), Some("octocat"));
+        assert_eq!(credentials.token.unwrap().expose(), "gho_token");
+
+        let credentials = parse_git_credentials(
+            "protocol=https\nhost=github.com\nusername=octocat\npassword=pat\n",
+        );
+        assert_eq!(credentials.user.as_deref(), Some("octocat"));
+        assert_eq!(credentials.token.unwrap().expose(), "pat");
+    }
+}
@@ -1,27 +1,62 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use async_trait::async_trait;
-use color_eyre::{eyre::Context, Result};
+use camino::Utf8Path;
+use chrono::Utc;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
 use lazy_static::lazy_static;
 use regex::Regex;
 use reqwest::{
-    header::{HeaderMap, ACCEPT, CONTENT_TYPE, USER_AGENT},
-    Client, ClientBuilder, Method, RequestBuilder,
+    header::{HeaderMap, ACCEPT, CONTENT_TYPE, ETAG, IF_NONE_MATCH, USER_AGENT},
+    Client, ClientBuilder, Method, RequestBuilder, StatusCode,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use tracing::{debug, info, instrument, trace};
 
 use crate::Repository;
 
+use super::cache::{self, CachedListing, CachedPage};
 use super::constants::OUR_USER_AGENT;
-use super::{check_api_errors, fetch_from_cache, save_to_cache, Provider};
+use super::credentials::{self, CredentialSource, Credentials, Secret};
+use super::github_app::GithubApp;
+use super::{check_api_errors, retry, Provider};
+
+mod graphql;
+
+const FORK_READY_ATTEMPTS: u32 = 30;
+const FORK_READY_DELAY: Duration = Duration::from_secs(2);
+
+/// Github only checks the token, this user works for app tokens and when the user is unknown
+const GIT_TOKEN_USER: &str = "x-access-token";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct GithubProvider {
-    user: String,
-    token: String,
+    /// Looked up on `credential_sources` when missing, like the token
+    user: Option<String>,
+    token: Option<Secret>,
     organization: String,
     #[serde(default = "default_url")]
     api_url: String,
+    #[serde(default = "credentials::default_sources")]
+    credential_sources: Vec<CredentialSource>,
+    /// Authenticates as a GitHub App installation, `user` and `token` are not used with it
+    app: Option<GithubApp>,
+    /// Repositories without push access are forked here and pull requests are opened from the fork
+    fork_owner: Option<String>,
+    /// Lists repositories and open pull requests with a few GraphQL queries instead of REST calls
+    #[serde(default)]
+    graphql: bool,
+    /// Open pull requests fetched ahead for all repositories, by repository and branch
+    #[serde(skip)]
+    open_prs: Arc<Mutex<HashMap<(String, String), bool>>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,22 +75,37 @@ struct PrCreateResponse {
 #[async_trait]
 impl Provider for GithubProvider {
     #[instrument(skip(self))]
-    async fn is_pr_open(&self, repository_name: &str, branch_name: &str) -> Result<bool> {
+    async fn is_pr_open(
+        &self,
+        repository_name: &str,
+        branch_name: &str,
+        fork_owner: Option<&str>,
+    ) -> Result<bool> {
+        let key = (repository_name.to_owned(), branch_name.to_owned());
+        if fork_owner.is_none() {
+            if let Some(open) = self.open_prs.lock().unwrap().get(&key) {
+                return Ok(*open);
+            }
+        }
         let url = format!(
             "{}/repos/{}/{}/pulls",
             self.api_url, self.organization, repository_name
         );
-        let head = format!("{}:{}", self.organization, branch_name);
-        let response = self
-            .request(Method::GET, &url)?
-            .query(&[("head", head.as_str()), ("state", "open")])
-            .send()
-            .await?;
+        let head = format!(
+            "{}:{}",
+            fork_owner.unwrap_or(&self.organization),
+            branch_name
+        );
+        let request = self
+            .request(Method::GET, &url)
+            .await?
+            .query(&[("head", head.as_str()), ("state", "open")]);
+        let response = retry::send(request).await?;
 
         let response = check_api_errors(response).await?;
         let body: Vec<Value> = response.json().await?;
         assert!(body.len() <= 1);
-        Ok(body.len() >= 1)
+        Ok(!body.is_empty())
     }
 
     #[instrument(skip(self),  fields(organization = self.organization.as_str()))]
@@ -78,11 +128,8 @@ impl Provider for GithubProvider {
             base,
             head,
         };
-        let response = self
-            .request(Method::POST, &url)?
-            .json(&payload)
-            .send()
-            .await?;
+        let request = self.request(Method::POST, &url).await?.json(&payload);
+        let response = retry::send(request).await?;
         let response = check_api_errors(response)
             .await
             .wrap_err("failed to open pr")?;
@@ -93,56 +140,234 @@ impl Provider for GithubProvider {
     }
 
     #[instrument(skip(self), fields(organization = self.organization.as_str()))]
-    async fn list_repositories(&self, use_cache: bool) -> Result<Vec<Repository>> {
-        if use_cache {
-            if let Some(repositories) = fetch_from_cache("github", &self.organization).await? {
+    async fn list_repositories(&self, max_age: Duration) -> Result<Vec<Repository>> {
+        let cached = cache::load("github", &self.organization, &self.api_url).await?;
+        let cached_pages = match cached {
+            Some(cached) if cached.is_fresh(max_age, Utc::now()) => {
                 trace!("using cached repositories");
-                return Ok(repositories);
+                return Ok(cached.into_repositories());
             }
-        }
+            Some(cached) => cached.pages,
+            None => vec![],
+        };
         trace!("fetching repositories");
-        let mut output = vec![];
+        let pages = if self.graphql {
+            vec![self.list_repositories_graphql().await?]
+        } else {
+            self.list_repository_pages(cached_pages).await?
+        };
+        let listing = CachedListing::new(&self.api_url, &self.clone_namespace(), pages);
+        cache::save("github", &self.organization, &listing).await?;
+        Ok(listing.into_repositories())
+    }
+
+    async fn prefetch_open_prs(
+        &self,
+        repository_names: &[String],
+        branch_name: &str,
+    ) -> Result<()> {
+        if !self.graphql {
+            return Ok(());
+        }
+        let open_prs = self.open_prs_graphql(repository_names, branch_name).await?;
+        self.open_prs.lock().unwrap().extend(
+            open_prs
+                .into_iter()
+                .map(|(name, open)| ((name, branch_name.to_owned()), open)),
+        );
+        Ok(())
+    }
+
+    fn clone_namespace(&self) -> String {
+        format!("github/{}/{}", self.host(), self.organization)
+    }
+
+    fn fork_owner(&self) -> Option<&str> {
+        self.fork_owner.as_deref()
+    }
+
+    #[instrument(skip(self), fields(fork_owner = ?self.fork_owner))]
+    async fn fork_repository(&self, repository_name: &str) -> Result<Repository> {
+        let owner = self
+            .fork_owner
+            .as_deref()
+            .ok_or_else(|| eyre!("fork_owner is not set"))?;
+        let url = format!(
+            "{}/repos/{}/{}/forks",
+            self.api_url, self.organization, repository_name
+        );
+        // Without an organization github forks into the authenticated user
+        let payload = if Some(owner) == self.user.as_deref() {
+            json!({})
+        } else {
+            json!({ "organization": owner })
+        };
+        let request = self.request(Method::POST, &url).await?.json(&payload);
+        let response = check_api_errors(retry::send(request).await?)
+            .await
+            .wrap_err("failed to create fork")?;
+        let fork: Repository = response.json().await?;
+        self.wait_for_fork(owner, &fork).await?;
+        Ok(fork)
+    }
+
+    async fn git_credentials(&self) -> Result<(String, Secret)> {
+        if let Some(app) = &self.app {
+            let token = app
+                .token(&client()?, &self.api_url, &self.organization)
+                .await?;
+            return Ok((GIT_TOKEN_USER.to_owned(), token));
+        }
+        let user = self.user.as_deref().unwrap_or(GIT_TOKEN_USER);
+        Ok((user.to_owned(), self.token()?.clone()))
+    }
+}
+
+impl GithubProvider {
+    /// Fills the user and token that are not on the plan from the configured sources,
+    /// relative paths are resolved from `plan_directory`
+    pub async fn resolve_credentials(&mut self, plan_directory: &Utf8Path) -> Result<()> {
+        if let Some(app) = &mut self.app {
+            app.resolve_paths(plan_directory);
+            return Ok(());
+        }
+        let mut credentials = Credentials {
+            user: self.user.take(),
+            token: self.token.take(),
+        };
+        credentials::resolve(
+            &mut credentials,
+            &self.host(),
+            "GITHUB",
+            &self.credential_sources,
+        )
+        .await?;
+        self.user = credentials.user;
+        self.token = credentials.token;
+        Ok(())
+    }
+
+    fn token(&self) -> Result<&Secret> {
+        self.token.as_ref().ok_or_else(|| {
+            eyre!("no github token found on the plan, environment, gh, git credentials or keyring")
+        })
+    }
+
+    /// Host used to look up credentials, `api.github.com` is `github.com`
+    fn host(&self) -> String {
+        let host = reqwest::Url::parse(&self.api_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+            .unwrap_or_else(|| "github.com".to_owned());
+        host.strip_prefix("api.").map(str::to_owned).unwrap_or(host)
+    }
+
+    /// Pages that did not change since the last listing are answered with 304 and taken from
+    /// the cache, which does not count against the rate limit
+    async fn list_repository_pages(
+        &self,
+        cached_pages: Vec<CachedPage>,
+    ) -> Result<Vec<CachedPage>> {
+        let mut cached_pages: HashMap<_, _> = cached_pages
+            .into_iter()
+            .map(|page| (page.url.clone(), page))
+            .collect();
+        let mut pages = vec![];
         let mut next_page_url = Some(format!(
             "{}/orgs/{}/repos?type=private&per_page=100&page=1",
             self.api_url, self.organization
         ));
-        while let Some(url) = next_page_url.as_ref() {
-            let (repositories, next_page) = self.list_repositories_per_page(&url).await?;
-            output.extend(repositories);
-            next_page_url = next_page
+        while let Some(url) = next_page_url {
+            let cached = cached_pages.remove(&url);
+            let page = self.list_repositories_per_page(url, cached).await?;
+            next_page_url = page.next.clone();
+            pages.push(page);
         }
-        save_to_cache("github", &self.organization, &output).await?;
-        Ok(output)
+        Ok(pages)
     }
-}
 
-impl GithubProvider {
-    #[instrument(skip(self))]
+    #[instrument(skip(self, cached))]
     async fn list_repositories_per_page(
         &self,
-        url: &str,
-    ) -> Result<(Vec<Repository>, Option<String>)> {
+        url: String,
+        cached: Option<CachedPage>,
+    ) -> Result<CachedPage> {
         debug!("Fetching repositories on {}", &url);
-        let response = self.request(Method::GET, &url)?.send().await?;
+        let mut request = self.request(Method::GET, &url).await?;
+        if let Some(etag) = cached.as_ref().and_then(|page| page.etag.as_deref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = retry::send(request).await?;
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+            trace!("page not modified");
+            return Ok(cached);
+        }
 
         let response = check_api_errors(response).await?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_owned);
         let link_header = response
             .headers()
             .get("link")
             .expect("github always send link")
             .to_str()
             .unwrap();
-        let next_page = get_next_url(link_header).map(|p| p.to_string());
+        let next = get_next_url(link_header).map(|p| p.to_string());
 
         let repositories: Vec<Repository> = response.json().await?;
 
-        Ok((repositories, next_page))
+        Ok(CachedPage {
+            url,
+            etag,
+            next,
+            repositories,
+        })
     }
 
-    fn request(&self, method: Method, url: &str) -> Result<RequestBuilder> {
-        Ok(client()?
+    /// Forks are created in the background, they can be pushed to once their default branch exists
+    async fn wait_for_fork(&self, owner: &str, fork: &Repository) -> Result<()> {
+        let url = format!(
+            "{}/repos/{}/{}/branches/{}",
+            self.api_url, owner, fork.name, fork.default_branch
+        );
+        for attempt in 0..FORK_READY_ATTEMPTS {
+            let response = retry::send(self.request(Method::GET, &url).await?).await?;
+            if response.status().is_success() {
+                return Ok(());
+            }
+            trace!(attempt, "fork is not ready yet");
+            tokio::time::sleep(FORK_READY_DELAY).await;
+        }
+        Err(eyre!("fork {}/{} did not become ready", owner, fork.name))
+    }
+
+    /// Installation token of the app or the token of the user
+    async fn api_token(&self) -> Result<Secret> {
+        match &self.app {
+            Some(app) => {
+                app.token(&client()?, &self.api_url, &self.organization)
+                    .await
+            }
+            None => Ok(self.token()?.clone()),
+        }
+    }
+
+    async fn request(&self, method: Method, url: &str) -> Result<RequestBuilder> {
+        let client = client()?;
+        if let Some(app) = &self.app {
+            let token = app
+                .token(&client, &self.api_url, &self.organization)
+                .await?;
+            return Ok(client.request(method, url).bearer_auth(token.expose()));
+        }
+        let token = self.token()?;
+        let user = self.user.as_deref().unwrap_or(GIT_TOKEN_USER);
+        Ok(client
             .request(method, url)
-            .basic_auth(&self.user, Some(&self.token)))
+            .basic_auth(user, Some(token.expose())))
     }
 }
 
@@ -166,18 +391,27 @@ fn get_next_url(link_header: &str) -> Option<&str> {
         static ref RE: Regex = Regex::new(r#"<(.+?)>; rel="next""#).unwrap();
     }
     RE.captures(link_header)
-        .map(|c| c.get(1))
-        .flatten()
+        .and_then(|c| c.get(1))
         .map(|m| m.as_str())
 }
 
 #[cfg(test)]
 mod tests {
+    #[cfg(docker)]
+    use std::time::Duration;
+
+    #[cfg(docker)]
     use stub_server::start_wiremock;
 
-    use crate::{providers::Provider, setup_error_handlers};
+    #[cfg(docker)]
+    use crate::{
+        providers::{credentials::Secret, Provider},
+        setup_error_handlers,
+    };
 
-    use super::{get_next_url, GithubProvider};
+    use super::get_next_url;
+    #[cfg(docker)]
+    use super::GithubProvider;
 
     #[cfg(docker)]
     #[tokio::test]
@@ -185,22 +419,30 @@ mod tests {
         setup_error_handlers().ok();
         let base_url = start_wiremock().await.unwrap();
         let provider = GithubProvider {
-            user: "test-user".to_string(),
-            token: "bebacafe".to_string(),
+            user: Some("test-user".to_string()),
+            token: Some(Secret::new("bebacafe")),
             organization: "fix-it".to_string(),
             api_url: format!("{}/github", base_url),
+            credential_sources: vec![],
+            app: None,
+            fork_owner: None,
+            graphql: false,
+            open_prs: Default::default(),
         };
 
-        let repositories = provider.list_repositories(false).await.unwrap();
+        let repositories = provider
+            .list_repositories(Duration::from_secs(0))
+            .await
+            .unwrap();
         assert_eq!(repositories.len(), 2);
         let repository = &repositories[0];
         assert_eq!(repository.name, "fix-it-1");
         assert!(provider
-            .is_pr_open("fix-it-1", "valid-branch")
+            .is_pr_open("fix-it-1", "valid-branch", None)
             .await
             .expect("failed to check if a pr for valid branch is open"));
         assert!(!provider
-            .is_pr_open("fix-it-1", "invalid-branch")
+            .is_pr_open("fix-it-1", "invalid-branch", None)
             .await
             .expect("failed to check if a pr for invalid branch is not open"));
         provider
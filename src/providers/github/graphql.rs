@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tracing::{debug, instrument, warn};
+
+use crate::providers::{cache::CachedPage, check_api_errors, retry};
+use crate::{Repository, RepositoryPermissions};
+
+use super::{client, GithubProvider};
+
+/// Repositories checked for open pull requests on each query, github limits the query complexity
+const PULL_REQUESTS_BATCH: usize = 50;
+
+const REPOSITORIES_QUERY: &str = r#"
+query($organization: String!, $cursor: String) {
+  organization(login: $organization) {
+    repositories(first: 100, after: $cursor, privacy: PRIVATE) {
+      pageInfo { hasNextPage endCursor }
+      nodes {
+        name
+        isPrivate
+        isFork
+        isArchived
+        viewerPermission
+        sshUrl
+        url
+        defaultBranchRef { name }
+        primaryLanguage { name }
+        repositoryTopics(first: 100) { nodes { topic { name } } }
+      }
+    }
+  }
+}
+"#;
+
+#[derive(Debug, Deserialize)]
+struct GraphqlResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlError {
+    message: String,
+    /// Field the error belongs to, starting with its alias
+    #[serde(default)]
+    path: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OrganizationData {
+    organization: Organization,
+}
+
+#[derive(Debug, Deserialize)]
+struct Organization {
+    repositories: Connection<RepositoryNode>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Connection<T> {
+    page_info: PageInfo,
+    nodes: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RepositoryNode {
+    name: String,
+    is_private: bool,
+    is_fork: bool,
+    is_archived: bool,
+    viewer_permission: Option<String>,
+    ssh_url: String,
+    url: String,
+    default_branch_ref: Option<Name>,
+    primary_language: Option<Name>,
+    repository_topics: Nodes<TopicNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Nodes<T> {
+    nodes: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopicNode {
+    topic: Name,
+}
+
+#[derive(Debug, Deserialize)]
+struct Name {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PullRequestsNode {
+    pull_requests: Nodes<PullRequestNode>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PullRequestNode {
+    head_repository_owner: Option<Login>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Login {
+    login: String,
+}
+
+impl From<RepositoryNode> for Repository {
+    fn from(node: RepositoryNode) -> Self {
+        Repository {
+            name: node.name,
+            private: node.is_private,
+            fork: node.is_fork,
+            archived: node.is_archived,
+            ssh_url: node.ssh_url,
+            clone_url: format!("{}.git", node.url),
+            // Empty repositories have no default branch, they are not listed
+            default_branch: node.default_branch_ref.map(|b| b.name).unwrap_or_default(),
+            language: node.primary_language.map(|l| l.name),
+            topics: node
+                .repository_topics
+                .nodes
+                .into_iter()
+                .map(|t| t.topic.name)
+                .collect(),
+            permissions: node
+                .viewer_permission
+                .map(|permission| RepositoryPermissions {
+                    push: matches!(permission.as_str(), "ADMIN" | "MAINTAIN" | "WRITE"),
+                }),
+        }
+    }
+}
+
+impl GithubProvider {
+    async fn graphql<T: DeserializeOwned>(&self, query: &str, variables: Value) -> Result<T> {
+        let response: GraphqlResponse<T> = self.graphql_response(query, variables).await?;
+        if !response.errors.is_empty() {
+            let messages = response
+                .errors
+                .into_iter()
+                .map(|e| e.message)
+                .collect::<Vec<_>>();
+            return Err(eyre!("graphql query failed: {}", messages.join(", ")));
+        }
+        response
+            .data
+            .ok_or_else(|| eyre!("graphql response has no data"))
+    }
+
+    /// Response with the errors of each field, which come along with the data of the others
+    async fn graphql_response<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: Value,
+    ) -> Result<GraphqlResponse<T>> {
+        let token = self.api_token().await?;
+        let request = client()?
+            .post(graphql_url(&self.api_url))
+            .bearer_auth(token.expose())
+            .json(&json!({ "query": query, "variables": variables }));
+        let response = check_api_errors(retry::send_read_only(request).await?).await?;
+        Ok(response.json().await?)
+    }
+
+    /// All repositories come in a single cached page, GraphQL has no conditional requests
+    #[instrument(skip(self), fields(organization = self.organization.as_str()))]
+    pub(super) async fn list_repositories_graphql(&self) -> Result<CachedPage> {
+        let mut output = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let data: OrganizationData = self
+                .graphql(
+                    REPOSITORIES_QUERY,
+                    json!({ "organization": self.organization, "cursor": cursor }),
+                )
+                .await?;
+            let repositories = data.organization.repositories;
+            debug!(count = repositories.nodes.len(), "fetched repositories");
+            // Empty repositories have no default branch and nothing to change
+            output.extend(repositories.nodes.into_iter().filter_map(|node| {
+                if node.default_branch_ref.is_none() {
+                    debug!(name = node.name.as_str(), "skipping empty repository");
+                    return None;
+                }
+                Some(Repository::from(node))
+            }));
+            match repositories.page_info {
+                PageInfo {
+                    has_next_page: true,
+                    end_cursor: Some(end_cursor),
+                } => cursor = Some(end_cursor),
+                _ => break,
+            }
+        }
+        Ok(CachedPage {
+            url: graphql_url(&self.api_url),
+            etag: None,
+            next: None,
+            repositories: output,
+        })
+    }
+
+    /// Whether each repository has an open pull request from `branch_name`, a few queries for all of
+    /// them. Repositories the query failed for, like renamed ones, are left out.
+    #[instrument(skip(self, repository_names), fields(repositories = repository_names.len()))]
+    pub(super) async fn open_prs_graphql(
+        &self,
+        repository_names: &[String],
+        branch_name: &str,
+    ) -> Result<HashMap<String, bool>> {
+        let mut output = HashMap::new();
+        for names in repository_names.chunks(PULL_REQUESTS_BATCH) {
+            let mut variables = json!({ "owner": self.organization, "branch": branch_name });
+            for (i, name) in names.iter().enumerate() {
+                variables[format!("r{}", i)] = json!(name);
+            }
+            let response = self
+                .graphql_response(&open_prs_query(names.len()), variables)
+                .await?;
+            output.extend(open_prs(names, response, &self.organization)?);
+        }
+        Ok(output)
+    }
+}
+
+/// Open pull requests per repository from an `open_prs_query` response
+fn open_prs(
+    names: &[String],
+    response: GraphqlResponse<HashMap<String, Option<PullRequestsNode>>>,
+    organization: &str,
+) -> Result<HashMap<String, bool>> {
+    let mut failed = vec![];
+    for error in &response.errors {
+        match error.path.first().and_then(Value::as_str) {
+            Some(alias) => failed.push(alias.to_owned()),
+            None => return Err(eyre!("graphql query failed: {}", error.message)),
+        }
+    }
+    if !failed.is_empty() {
+        warn!(
+            errors = ?response.errors.iter().map(|e| &e.message).collect::<Vec<_>>(),
+            "some repositories could not be checked for open pull requests"
+        );
+    }
+    let data = response
+        .data
+        .ok_or_else(|| eyre!("graphql response has no data"))?;
+    let mut output = HashMap::new();
+    for (i, name) in names.iter().enumerate() {
+        let alias = format!("r{}", i);
+        if failed.contains(&alias) {
+            continue;
+        }
+        let open = data
+            .get(&alias)
+            .and_then(Option::as_ref)
+            .map(|r| {
+                r.pull_requests.nodes.iter().any(|pr| {
+                    pr.head_repository_owner
+                        .as_ref()
+                        .map(|o| o.login.eq_ignore_ascii_case(organization))
+                        .unwrap_or(false)
+                })
+            })
+            .unwrap_or(false);
+        output.insert(name.clone(), open);
+    }
+    Ok(output)
+}
+
+/// One aliased `repository` per name, names are passed as variables so they need no escaping
+fn open_prs_query(count: usize) -> String {
+    let parameters = (0..count)
+        .map(|i| format!(", $r{}: String!", i))
+        .collect::<String>();
+    let repositories = (0..count)
+        .map(|i| {
+            format!(
+                "  r{0}: repository(owner: $owner, name: $r{0}) {{ pullRequests(headRefName: $branch, states: OPEN, first: 10) {{ nodes {{ headRepositoryOwner {{ login }} }} }} }}\n",
+                i
+            )
+        })
+        .collect::<String>();
+    format!(
+        "query($owner: String!, $branch: String!{}) {{\n{}}}",
+        parameters, repositories
+    )
+}
+
+/// `https://api.github.com/graphql` or `https://<host>/api/graphql` on github enterprise
+fn graphql_url(api_url: &str) -> String {
+    let api_url = api_url.trim_end_matches('/');
+    match api_url.strip_suffix("/v3") {
+        Some(base) => format!("{}/graphql", base),
+        None => format!("{}/graphql", api_url),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{graphql_url, open_prs, open_prs_query, RepositoryNode};
+    use crate::Repository;
+
+    #[test]
+    fn test_graphql_url() {
+        assert_eq!(
+            graphql_url("https://api.github.com"),
+            "https://api.github.com/graphql"
+        );
+        assert_eq!(
+            graphql_url("https://github.example.com/api/v3/"),
+            "https://github.example.com/api/graphql"
+        );
+    }
+
+    #[test]
+    fn test_open_prs_query() {
+        let query = open_prs_query(2);
+        assert!(query
+            .starts_with("query($owner: String!, $branch: String!, $r0: String!, $r1: String!) {"));
+        assert!(query.contains("r1: repository(owner: $owner, name: $r1)"));
+    }
+
+    #[test]
+    fn test_open_prs_with_errors() {
+        let response = serde_json::from_str(
+            r#"{
+                "data": {
+                    "r0": { "pullRequests": { "nodes": [{ "headRepositoryOwner": { "login": "Org" } }] } },
+                    "r1": null,
+                    "r2": { "pullRequests": { "nodes": [] } }
+                },
+                "errors": [{ "type": "NOT_FOUND", "path": ["r1"], "message": "Could not resolve to a Repository" }]
+            }"#,
+        )
+        .unwrap();
+        let names = ["open", "renamed", "closed"].map(String::from);
+
+        let open = open_prs(&names, response, "org").unwrap();
+        assert_eq!(open.get("open"), Some(&true));
+        assert_eq!(open.get("renamed"), None);
+        assert_eq!(open.get("closed"), Some(&false));
+
+        let response =
+            serde_json::from_str(r#"{ "data": null, "errors": [{ "message": "Bad query" }] }"#)
+                .unwrap();
+        assert!(open_prs(&names, response, "org").is_err());
+    }
+
+    #[test]
+    fn test_repository_node() {
+        let node: RepositoryNode = serde_json::from_str(
+            r#"{
+                "name": "fix-it",
+                "isPrivate": true,
+                "isFork": false,
+                "isArchived": true,
+                "viewerPermission": "READ",
+                "sshUrl": "git@github.com:org/fix-it.git",
+                "url": "https://github.com/org/fix-it",
+                "defaultBranchRef": { "name": "main" },
+                "primaryLanguage": null,
+                "repositoryTopics": { "nodes": [{ "topic": { "name": "backend" } }] }
+            }"#,
+        )
+        .unwrap();
+        let repository = Repository::from(node);
+
+        assert!(repository.archived);
+        assert!(!repository.can_push());
+        assert_eq!(repository.clone_url, "https://github.com/org/fix-it.git");
+        assert_eq!(repository.default_branch, "main");
+        assert_eq!(repository.topics, vec!["backend"]);
+    }
+}
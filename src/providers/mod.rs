@@ -1,23 +1,31 @@
+pub mod cache;
 mod constants;
+pub mod credentials;
 mod github;
+mod github_app;
+mod retry;
 #[cfg(test)]
 pub(crate) mod tests;
 
+use std::time::Duration;
+
 use async_trait::async_trait;
-use color_eyre::{
-    eyre::{eyre, Context},
-    Help, Result, SectionExt,
-};
-use serde::{de::DeserializeOwned, Serialize};
-use tokio::fs;
+use color_eyre::{eyre::eyre, Help, Result, SectionExt};
 
-use crate::{constants::CACHE_DIR, Repository};
+use crate::Repository;
 
+use self::credentials::Secret;
 pub use self::github::GithubProvider;
 
 #[async_trait]
 pub trait Provider: Sync + Send {
-    async fn is_pr_open(&self, repository_name: &str, branch_name: &str) -> Result<bool>;
+    /// `fork_owner` is set when the pull request comes from a fork
+    async fn is_pr_open(
+        &self,
+        repository_name: &str,
+        branch_name: &str,
+        fork_owner: Option<&str>,
+    ) -> Result<bool>;
     async fn open_pr(
         &self,
         repository_name: &str,
@@ -26,7 +34,29 @@ pub trait Provider: Sync + Send {
         title: &str,
         body: Option<&str>,
     ) -> Result<()>;
-    async fn list_repositories(&self, use_cache: bool) -> Result<Vec<Repository>>;
+    /// Cached listings younger than `max_age` are used as they are, older ones are refreshed
+    async fn list_repositories(&self, max_age: Duration) -> Result<Vec<Repository>>;
+    /// Lets providers find out which repositories have open pull requests with fewer requests
+    /// before `is_pr_open` is called for each of them
+    async fn prefetch_open_prs(
+        &self,
+        _repository_names: &[String],
+        _branch_name: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// User and token used by git over https
+    async fn git_credentials(&self) -> Result<(String, Secret)>;
+    /// Folder of the clones under the cache, as `<provider>/<host>/<organization>`
+    fn clone_namespace(&self) -> String;
+    /// Account or organization where repositories without push access are forked
+    fn fork_owner(&self) -> Option<&str> {
+        None
+    }
+    /// Forks a repository into `fork_owner` and waits until it can be pushed to
+    async fn fork_repository(&self, _repository_name: &str) -> Result<Repository> {
+        Err(eyre!("forks are not supported by this provider"))
+    }
 }
 
 pub(crate) async fn check_api_errors(response: reqwest::Response) -> Result<reqwest::Response> {
@@ -34,47 +64,8 @@ pub(crate) async fn check_api_errors(response: reqwest::Response) -> Result<reqw
         Err(source) => match response.text().await {
             Ok(body) => Err(eyre!(source)
                 .with_section(move || body.trim().to_string().header("Body: ").to_string())),
-            Err(err) => {
-                return Err(eyre!(err));
-            }
+            Err(err) => Err(eyre!(err)),
         },
         _ => Ok(response),
     }
 }
-
-pub(crate) async fn fetch_from_cache<T>(
-    provider_name: &str,
-    organization: &str,
-) -> Result<Option<T>>
-where
-    T: DeserializeOwned,
-{
-    let file = CACHE_DIR.join(format!(
-        "{}-{}-{}.json",
-        "repositories", provider_name, organization
-    ));
-    if !file.exists() {
-        return Ok(None);
-    }
-
-    Ok(serde_json::from_slice(&fs::read(file).await?)?)
-}
-
-pub(crate) async fn save_to_cache<T>(provider_name: &str, organization: &str, data: T) -> Result<()>
-where
-    T: Serialize,
-{
-    let contents = serde_json::to_vec_pretty(&data)?;
-    let path = CACHE_DIR.join(format!(
-        "{}-{}-{}.json",
-        "repositories", provider_name, organization
-    ));
-    let project_folder = path
-        .parent()
-        .ok_or_else(|| eyre!("failed to determine project folder"))?;
-    fs::create_dir_all(&project_folder).await?;
-    fs::write(&path, &contents)
-        .await
-        .wrap_err_with(|| format!("failed to save cache {}", path))?;
-    Ok(())
-}
@@ -0,0 +1,204 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::{eyre::eyre, Help, Result, SectionExt};
+use reqwest::{header::HeaderMap, Method, RequestBuilder, Response, StatusCode};
+use tracing::warn;
+
+const MAX_RETRIES: u32 = 5;
+/// Rate limits can last long, waiting them out is counted apart from errors
+const MAX_RATE_LIMIT_WAITS: u32 = 30;
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Github asks to wait at least a minute when a secondary rate limit has no Retry-After
+const SECONDARY_LIMIT_DELAY: Duration = Duration::from_secs(60);
+
+/// Sends a request, waiting out rate limits and retrying server and connection errors
+/// with jittered exponential backoff. Requests that are not idempotent, like opening a pull
+/// request, are only retried when they were surely not processed.
+pub(crate) async fn send(request: RequestBuilder) -> Result<Response> {
+    let method = request
+        .try_clone()
+        .and_then(|r| r.build().ok())
+        .map(|r| r.method().clone())
+        .unwrap_or(Method::POST);
+    send_retrying(request, is_idempotent(&method)).await
+}
+
+/// Like `send`, for POST requests that only read, like GraphQL queries
+pub(crate) async fn send_read_only(request: RequestBuilder) -> Result<Response> {
+    send_retrying(request, true).await
+}
+
+async fn send_retrying(request: RequestBuilder, idempotent: bool) -> Result<Response> {
+    let mut attempt = 0;
+    let mut rate_limit_waits = 0;
+    loop {
+        let this_request = request
+            .try_clone()
+            .ok_or_else(|| eyre!("request cannot be retried"))?;
+        let delay = match this_request.send().await {
+            Ok(response) => match retry_delay(response, attempt).await {
+                Retry::No(response) => return Ok(response),
+                Retry::RateLimited(delay) if rate_limit_waits < MAX_RATE_LIMIT_WAITS => {
+                    rate_limit_waits += 1;
+                    delay
+                }
+                Retry::RateLimited(_) => {
+                    return Err(eyre!(
+                        "gave up after waiting for {} rate limits",
+                        rate_limit_waits
+                    ))
+                }
+                Retry::After(response, _) if !idempotent => return Ok(response),
+                Retry::After(_, delay) if attempt < MAX_RETRIES => {
+                    attempt += 1;
+                    delay
+                }
+                Retry::After(..) => return Err(eyre!("gave up after {} retries", attempt)),
+                Retry::Failed(err) => return Err(err),
+            },
+            // Timeouts may come after the request was processed, connection errors never do
+            Err(err)
+                if (err.is_connect() || idempotent && err.is_timeout())
+                    && attempt < MAX_RETRIES =>
+            {
+                warn!(%err, "request failed");
+                attempt += 1;
+                backoff(attempt - 1)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        warn!(
+            attempt,
+            rate_limit_waits,
+            delay_seconds = delay.as_secs(),
+            "retrying request"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+    )
+}
+
+enum Retry {
+    No(Response),
+    /// Throttled requests were not processed, so they are retried whatever the method
+    RateLimited(Duration),
+    /// Server errors, the response is kept for requests that cannot be retried
+    After(Response, Duration),
+    /// Forbidden for reasons other than rate limits
+    Failed(color_eyre::Report),
+}
+
+async fn retry_delay(response: Response, attempt: u32) -> Retry {
+    let status = response.status();
+    if let Some(delay) = rate_limit_delay(status, response.headers(), SystemTime::now()) {
+        return Retry::RateLimited(delay);
+    }
+    if status.is_server_error() {
+        return Retry::After(response, backoff(attempt));
+    }
+    if status != StatusCode::FORBIDDEN {
+        return Retry::No(response);
+    }
+    // Secondary rate limits are only told apart by their message
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(err) => return Retry::Failed(err.into()),
+    };
+    if body.to_lowercase().contains("secondary rate limit") {
+        return Retry::RateLimited(SECONDARY_LIMIT_DELAY.max(backoff(attempt)));
+    }
+    Retry::Failed(
+        eyre!("HTTP status client error ({})", status)
+            .with_section(move || body.trim().to_string().header("Body: ")),
+    )
+}
+
+/// How long to wait when github says the request was throttled
+fn rate_limit_delay(status: StatusCode, headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(seconds) = header("retry-after").and_then(|v| v.parse().ok()) {
+        return Some(Duration::from_secs(seconds));
+    }
+    if header("x-ratelimit-remaining") != Some("0") {
+        return None;
+    }
+    let reset = header("x-ratelimit-reset").and_then(|v| v.parse().ok())?;
+    let reset = UNIX_EPOCH + Duration::from_secs(reset);
+    let wait = reset.duration_since(now).unwrap_or_default();
+    // A second more as the reset time is rounded down
+    Some(wait + Duration::from_secs(1))
+}
+
+/// Exponential backoff with jitter between half and all of the delay, so parallel
+/// executors do not retry in lockstep
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let jitter = 0.5 + f64::from(nanos % 1000) / 2000.0;
+    delay.mul_f64(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use reqwest::{header::HeaderMap, Method, StatusCode};
+
+    use super::{backoff, is_idempotent, rate_limit_delay, MAX_BACKOFF};
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "1030".parse().unwrap());
+
+        assert_eq!(
+            rate_limit_delay(StatusCode::FORBIDDEN, &headers, now),
+            Some(Duration::from_secs(31))
+        );
+        assert_eq!(rate_limit_delay(StatusCode::OK, &headers, now), None);
+
+        headers.insert("retry-after", "5".parse().unwrap());
+        assert_eq!(
+            rate_limit_delay(StatusCode::TOO_MANY_REQUESTS, &headers, now),
+            Some(Duration::from_secs(5))
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", "10".parse().unwrap());
+        assert_eq!(rate_limit_delay(StatusCode::FORBIDDEN, &headers, now), None);
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    }
+
+    #[test]
+    fn test_backoff() {
+        for attempt in 0..10 {
+            let delay = backoff(attempt);
+            let full = Duration::from_secs(1 << attempt.min(6)).min(MAX_BACKOFF);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+}
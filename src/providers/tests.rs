@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
 use color_eyre::Result;
 use serde::Deserialize;
@@ -5,7 +7,7 @@ use tracing::instrument;
 
 use crate::Repository;
 
-use super::Provider;
+use super::{credentials::Secret, Provider};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct TestProvider;
@@ -13,7 +15,12 @@ pub struct TestProvider;
 #[async_trait]
 impl Provider for TestProvider {
     #[instrument(skip(self))]
-    async fn is_pr_open(&self, _repository_name: &str, _branch_namee: &str) -> Result<bool> {
+    async fn is_pr_open(
+        &self,
+        _repository_name: &str,
+        _branch_namee: &str,
+        _fork_owner: Option<&str>,
+    ) -> Result<bool> {
         Ok(false)
     }
 
@@ -30,13 +37,26 @@ impl Provider for TestProvider {
     }
 
     #[instrument(skip(self))]
-    async fn list_repositories(&self, _use_cache: bool) -> Result<Vec<Repository>> {
+    async fn list_repositories(&self, _max_age: Duration) -> Result<Vec<Repository>> {
         Ok(vec![Repository {
             name: "working-repo".to_string(),
             private: true,
             fork: false,
+            archived: false,
             ssh_url: "any-url".to_string(),
+            clone_url: "https://any-url".to_string(),
             default_branch: "main".to_string(),
+            language: Some("Python".to_string()),
+            topics: vec![],
+            permissions: None,
         }])
     }
+
+    fn clone_namespace(&self) -> String {
+        "test/localhost/test-organization".to_string()
+    }
+
+    async fn git_credentials(&self) -> Result<(String, Secret)> {
+        Ok(("test-user".to_string(), Secret::new("test-token")))
+    }
 }
@@ -16,6 +16,10 @@ if [ -z "$(git config --global user.name)" ]; then
     git config --global user.name "Test User"
 fi
 echo "enabled = True" > file.py
+echo "language: python" > .travis.yml
+mkdir docs vendor
+echo "enabled = True" > vendor/lib.py
+echo "Title" > docs/index.rst
 git add .
 git commit -m"Initial commit"
 git branch -M main